@@ -0,0 +1,202 @@
+use std::time::Duration;
+
+use chrono::{NaiveTime, Utc};
+use log::debug;
+use tokio::sync::mpsc::WeakSender;
+use tokio::time::{self, MissedTickBehavior};
+
+use crate::outbound_queue::QueueReceiver;
+use crate::types::{ClientReply, RouterMessage, Time, UserTextMessage};
+
+mod deploy;
+mod dice;
+mod standup;
+
+pub(crate) use deploy::DeployBot;
+pub(crate) use dice::DiceBot;
+pub(crate) use standup::StandupBot;
+
+/// How often bots get a `tick`.
+const TICK: Duration = Duration::from_secs(30);
+
+/// Something a bot's room saw.
+#[derive(Debug)]
+pub(crate) enum BotEvent<'a> {
+    /// A new message from anyone but the bot itself.
+    Message(&'a UserTextMessage),
+    Joined {
+        user_id: u128,
+        display_name: &'a str,
+        bot: bool,
+    },
+    Left {
+        user_id: u128,
+    },
+}
+
+/// A bot living in one room. Whatever it returns is sent to the room as its
+/// own messages, through the same router path as a person typing them.
+pub(crate) trait Bot: Send {
+    fn on_event(&mut self, event: &BotEvent) -> Vec<String>;
+
+    /// Called every `TICK`, for bots that act on a schedule.
+    fn tick(&mut self, _now: Time) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// The bots that ship with the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum BotKind {
+    /// Answers `!roll`.
+    Dice,
+    /// Echoes `!deploy` as a deploy notice.
+    Deploy,
+    /// Reminds the room about stand-up at `at` UTC on weekdays.
+    Standup { at: NaiveTime },
+}
+
+impl BotKind {
+    pub fn username(&self) -> &'static str {
+        match self {
+            BotKind::Dice => "dicebot",
+            BotKind::Deploy => "deploybot",
+            BotKind::Standup { .. } => "standupbot",
+        }
+    }
+
+    pub fn display_name(&self) -> &'static str {
+        match self {
+            BotKind::Dice => "Dice",
+            BotKind::Deploy => "Deploys",
+            BotKind::Standup { .. } => "Stand-up",
+        }
+    }
+
+    pub fn build(&self) -> Box<dyn Bot> {
+        match *self {
+            BotKind::Dice => Box::new(DiceBot::new()),
+            BotKind::Deploy => Box::new(DeployBot),
+            BotKind::Standup { at } => Box::new(StandupBot::new(at)),
+        }
+    }
+}
+
+/// A bot and the rooms, by name, it sits in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BotSpec {
+    pub kind: BotKind,
+    pub rooms: Vec<String>,
+}
+
+/// Reads a semicolon separated list of `bot=room,room`, for example
+/// `dice=general,random;standup=general;deploy=ops`.
+pub(crate) fn parse_specs(spec: &str, standup_at: NaiveTime) -> Result<Vec<BotSpec>, String> {
+    let mut specs = Vec::new();
+    for entry in spec.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+        let (name, rooms) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("expected bot=rooms, got {:?}", entry))?;
+        let kind = match name.trim() {
+            "dice" => BotKind::Dice,
+            "deploy" => BotKind::Deploy,
+            "standup" => BotKind::Standup { at: standup_at },
+            other => return Err(format!("unknown bot {:?}", other)),
+        };
+        let rooms = rooms
+            .split(',')
+            .map(str::trim)
+            .filter(|room| !room.is_empty())
+            .map(str::to_string)
+            .collect();
+        specs.push(BotSpec { kind, rooms });
+    }
+    Ok(specs)
+}
+
+/// Runs one bot in one room for as long as the router keeps its session.
+pub(crate) async fn run(
+    mut bot: Box<dyn Bot>,
+    user_id: u128,
+    room_id: u128,
+    session_id: u128,
+    mut reply_rx: QueueReceiver,
+    router_tx: WeakSender<RouterMessage>,
+) {
+    let mut tick = time::interval(TICK);
+    tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        let said = tokio::select! {
+            reply = reply_rx.recv() => match reply {
+                Some(reply) => handle(bot.as_mut(), user_id, room_id, &reply),
+                None => break,
+            },
+            _ = tick.tick() => bot.tick(Utc::now()),
+        };
+
+        for message in said {
+            let Some(router_tx) = router_tx.upgrade() else {
+                return;
+            };
+            let msg = RouterMessage::SendMessage {
+                room_id,
+                message,
+                parent_id: None,
+                session_id,
+            };
+            if router_tx.send(msg).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    debug!("Bot session {} in room {} stopped", session_id, room_id);
+}
+
+/// Hands the bot whatever in the reply it cares about.
+fn handle(bot: &mut dyn Bot, me: u128, room_id: u128, reply: &ClientReply) -> Vec<String> {
+    let event = match reply {
+        ClientReply::NewMessage { message }
+            if message.room_id() == room_id && message.from() != me =>
+        {
+            BotEvent::Message(message)
+        }
+        ClientReply::MemberJoined {
+            room_id: joined,
+            user_id,
+            display_name,
+            bot,
+        } if *joined == room_id => BotEvent::Joined {
+            user_id: *user_id,
+            display_name,
+            bot: *bot,
+        },
+        ClientReply::MemberLeft {
+            room_id: left,
+            user_id,
+        } if *left == room_id => BotEvent::Left { user_id: *user_id },
+        _ => return Vec::new(),
+    };
+    bot.on_event(&event)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use chrono::Utc;
+
+    use crate::types::UserTextMessage;
+
+    /// A message from alice in room 2.
+    pub(crate) fn message(text: &str) -> UserTextMessage {
+        UserTextMessage::new(
+            1,
+            2,
+            1,
+            Utc::now(),
+            text.to_string(),
+            3,
+            "alice".to_string(),
+        )
+    }
+}
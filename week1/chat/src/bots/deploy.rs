@@ -0,0 +1,72 @@
+use super::{Bot, BotEvent};
+
+const USAGE: &str = "Usage: !deploy <what went out>, for example !deploy api v1.4.2";
+
+/// Echoes `!deploy api v1.4.2` back to the room as a deploy notice, so CI
+/// posting through the HTTP API and people announcing by hand look the same.
+pub(crate) struct DeployBot;
+
+impl Bot for DeployBot {
+    fn on_event(&mut self, event: &BotEvent) -> Vec<String> {
+        let BotEvent::Message(message) = event else {
+            return Vec::new();
+        };
+        if message.deleted() {
+            return Vec::new();
+        }
+        let text = message.text().trim();
+        let (command, what) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        if !command.eq_ignore_ascii_case("!deploy") {
+            return Vec::new();
+        }
+        let what = what.trim();
+        if what.is_empty() {
+            return vec![USAGE.to_string()];
+        }
+        vec![format!(
+            "Deployed: {} (announced by {})",
+            what,
+            message.username()
+        )]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bots::tests::message;
+
+    fn say(text: &str) -> Vec<String> {
+        DeployBot.on_event(&BotEvent::Message(&message(text)))
+    }
+
+    #[test]
+    fn echoes_deploys() {
+        assert_eq!(
+            say("!deploy api v1.4.2 to production"),
+            ["Deployed: api v1.4.2 to production (announced by alice)"]
+        );
+        assert_eq!(
+            say("!DEPLOY  web  "),
+            ["Deployed: web (announced by alice)"]
+        );
+    }
+
+    #[test]
+    fn explains_an_empty_deploy() {
+        assert_eq!(say("!deploy"), [USAGE]);
+        assert_eq!(say("!deploy   "), [USAGE]);
+    }
+
+    #[test]
+    fn ignores_everything_else() {
+        assert!(say("we deploy on fridays").is_empty());
+        assert!(say("!deployed api").is_empty());
+        assert!(DeployBot
+            .on_event(&BotEvent::Message(&message("!deploy api").tombstone()))
+            .is_empty());
+        assert!(DeployBot
+            .on_event(&BotEvent::Left { user_id: 3 })
+            .is_empty());
+    }
+}
@@ -0,0 +1,142 @@
+use rand::Rng;
+
+use super::{Bot, BotEvent};
+
+const USAGE: &str = "Usage: !roll [count]d<sides>, for example !roll 2d6";
+const MAX_DICE: u32 = 100;
+const MAX_SIDES: u32 = 1000;
+
+/// Answers `!roll 2d6` with the dice and their total. A bare `!roll` is 1d6.
+pub(crate) struct DiceBot {
+    /// Given the number of sides, a roll from 1 up to it.
+    roller: Box<dyn FnMut(u32) -> u32 + Send>,
+}
+
+impl DiceBot {
+    pub fn new() -> Self {
+        DiceBot::with_roller(|sides| rand::thread_rng().gen_range(1..=sides))
+    }
+
+    pub fn with_roller(roller: impl FnMut(u32) -> u32 + Send + 'static) -> Self {
+        DiceBot {
+            roller: Box::new(roller),
+        }
+    }
+
+    fn roll(&mut self, who: &str, dice: &str) -> String {
+        let Some((count, sides)) = parse_dice(dice) else {
+            return USAGE.to_string();
+        };
+        let rolls: Vec<u32> = (0..count).map(|_| (self.roller)(sides)).collect();
+        let total: u32 = rolls.iter().sum();
+        if count == 1 {
+            return format!("{} rolled 1d{}: {}", who, sides, total);
+        }
+        let shown: Vec<String> = rolls.iter().map(u32::to_string).collect();
+        format!(
+            "{} rolled {}d{}: {} = {}",
+            who,
+            count,
+            sides,
+            shown.join(" + "),
+            total
+        )
+    }
+}
+
+impl Bot for DiceBot {
+    fn on_event(&mut self, event: &BotEvent) -> Vec<String> {
+        let BotEvent::Message(message) = event else {
+            return Vec::new();
+        };
+        if message.deleted() {
+            return Vec::new();
+        }
+        let mut words = message.text().split_whitespace();
+        if !words
+            .next()
+            .is_some_and(|word| word.eq_ignore_ascii_case("!roll"))
+        {
+            return Vec::new();
+        }
+        let reply = match (words.next(), words.next()) {
+            (None, _) => self.roll(message.username(), "1d6"),
+            (Some(dice), None) => self.roll(message.username(), dice),
+            _ => USAGE.to_string(),
+        };
+        vec![reply]
+    }
+}
+
+/// `2d6` as `(2, 6)`, with the count optional.
+fn parse_dice(dice: &str) -> Option<(u32, u32)> {
+    let dice = dice.to_ascii_lowercase();
+    let (count, sides) = dice.split_once('d')?;
+    let count = if count.is_empty() {
+        1
+    } else {
+        count.parse().ok()?
+    };
+    let sides = sides.parse().ok()?;
+    ((1..=MAX_DICE).contains(&count) && (2..=MAX_SIDES).contains(&sides)).then_some((count, sides))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bots::tests::message;
+
+    /// Rolls 1, 2, 3 and so on, whatever the dice.
+    fn counting() -> DiceBot {
+        let mut next = 0;
+        DiceBot::with_roller(move |_| {
+            next += 1;
+            next
+        })
+    }
+
+    fn say(bot: &mut DiceBot, text: &str) -> Vec<String> {
+        bot.on_event(&BotEvent::Message(&message(text)))
+    }
+
+    #[test]
+    fn rolls_and_adds_up() {
+        let mut bot = counting();
+        assert_eq!(
+            say(&mut bot, "!roll 3d6"),
+            ["alice rolled 3d6: 1 + 2 + 3 = 6"]
+        );
+    }
+
+    #[test]
+    fn defaults_to_one_six_sided_die() {
+        let mut bot = DiceBot::with_roller(|sides| sides);
+        assert_eq!(say(&mut bot, "!roll"), ["alice rolled 1d6: 6"]);
+        assert_eq!(say(&mut bot, "!ROLL d20"), ["alice rolled 1d20: 20"]);
+    }
+
+    #[test]
+    fn explains_bad_dice() {
+        let mut bot = counting();
+        for text in [
+            "!roll 0d6",
+            "!roll 2d1",
+            "!roll 101d6",
+            "!roll 2d6 extra",
+            "!roll six",
+        ] {
+            assert_eq!(say(&mut bot, text), [USAGE], "{}", text);
+        }
+    }
+
+    #[test]
+    fn ignores_everything_else() {
+        let mut bot = counting();
+        assert!(say(&mut bot, "let's roll 2d6").is_empty());
+        assert!(say(&mut bot, "!rolling").is_empty());
+        assert!(bot
+            .on_event(&BotEvent::Message(&message("!roll").tombstone()))
+            .is_empty());
+        assert!(bot.on_event(&BotEvent::Left { user_id: 3 }).is_empty());
+    }
+}
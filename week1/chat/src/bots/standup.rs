@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+
+use chrono::{Datelike, NaiveDate, NaiveTime, TimeDelta, Weekday};
+
+use super::{Bot, BotEvent};
+use crate::types::Time;
+
+/// How late after `at` the reminder still goes out, say after a restart.
+const GRACE: TimeDelta = TimeDelta::minutes(15);
+
+/// Reminds the room about stand-up once each weekday at `at` UTC, and says
+/// when it is if asked with `!standup`.
+pub(crate) struct StandupBot {
+    at: NaiveTime,
+    last_posted: Option<NaiveDate>,
+    /// People seen joining or talking since the bot started, and not seen
+    /// leaving since. The reminder calls them by name.
+    here: HashMap<u128, String>,
+}
+
+impl StandupBot {
+    pub fn new(at: NaiveTime) -> Self {
+        StandupBot {
+            at,
+            last_posted: None,
+            here: HashMap::new(),
+        }
+    }
+
+    fn due(&self, now: Time) -> bool {
+        let late = now.time().signed_duration_since(self.at);
+        is_weekday(now.weekday())
+            && self.last_posted != Some(now.date_naive())
+            && late >= TimeDelta::zero()
+            && late < GRACE
+    }
+
+    fn reminder(&self) -> String {
+        let mut names: Vec<&str> = self.here.values().map(String::as_str).collect();
+        names.sort_unstable();
+        let who = if names.is_empty() {
+            String::new()
+        } else {
+            format!(", {}", names.join(", "))
+        };
+        format!(
+            "Stand-up time{}! What did you do yesterday, what's next, and is anything in your way?",
+            who
+        )
+    }
+}
+
+impl Bot for StandupBot {
+    fn on_event(&mut self, event: &BotEvent) -> Vec<String> {
+        match *event {
+            BotEvent::Message(message) if !message.deleted() => {
+                self.here
+                    .entry(message.from())
+                    .or_insert_with(|| message.username().to_string());
+                if message.text().trim().eq_ignore_ascii_case("!standup") {
+                    return vec![format!(
+                        "Stand-up is at {} UTC, Monday to Friday",
+                        self.at.format("%H:%M")
+                    )];
+                }
+            }
+            BotEvent::Joined {
+                user_id,
+                display_name,
+                bot: false,
+            } => {
+                self.here.insert(user_id, display_name.to_string());
+            }
+            BotEvent::Left { user_id } => {
+                self.here.remove(&user_id);
+            }
+            _ => {}
+        }
+        Vec::new()
+    }
+
+    fn tick(&mut self, now: Time) -> Vec<String> {
+        if !self.due(now) {
+            return Vec::new();
+        }
+        self.last_posted = Some(now.date_naive());
+        vec![self.reminder()]
+    }
+}
+
+fn is_weekday(day: Weekday) -> bool {
+    !matches!(day, Weekday::Sat | Weekday::Sun)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::bots::tests::message;
+
+    fn bot() -> StandupBot {
+        StandupBot::new(NaiveTime::from_hms_opt(9, 30, 0).unwrap())
+    }
+
+    /// 2024-06-03 was a Monday.
+    fn at(day: u32, hour: u32, minute: u32) -> Time {
+        Utc.with_ymd_and_hms(2024, 6, day, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn reminds_once_a_weekday() {
+        let mut bot = bot();
+        assert!(bot.tick(at(3, 9, 29)).is_empty());
+        assert_eq!(bot.tick(at(3, 9, 30)).len(), 1);
+        assert!(bot.tick(at(3, 9, 31)).is_empty());
+        assert_eq!(bot.tick(at(4, 9, 35)).len(), 1);
+    }
+
+    #[test]
+    fn skips_weekends_and_late_starts() {
+        let mut bot = bot();
+        assert!(bot.tick(at(1, 9, 30)).is_empty());
+        assert!(bot.tick(at(2, 9, 30)).is_empty());
+        assert!(bot.tick(at(3, 9, 45)).is_empty());
+        assert!(bot.tick(at(3, 12, 0)).is_empty());
+    }
+
+    #[test]
+    fn calls_on_whoever_is_here() {
+        let mut bot = bot();
+        bot.on_event(&BotEvent::Message(&message("morning")));
+        for (user_id, display_name, is_bot) in
+            [(4, "Carol", false), (5, "Bob", false), (6, "Dice", true)]
+        {
+            bot.on_event(&BotEvent::Joined {
+                user_id,
+                display_name,
+                bot: is_bot,
+            });
+        }
+        bot.on_event(&BotEvent::Left { user_id: 4 });
+
+        assert_eq!(
+            bot.tick(at(3, 9, 30)),
+            ["Stand-up time, Bob, alice! What did you do yesterday, what's next, and is anything in your way?"]
+        );
+    }
+
+    #[test]
+    fn answers_standup() {
+        let mut bot = bot();
+        assert_eq!(
+            bot.on_event(&BotEvent::Message(&message(" !standup "))),
+            ["Stand-up is at 09:30 UTC, Monday to Friday"]
+        );
+        assert!(bot
+            .on_event(&BotEvent::Message(&message("standup soon?")))
+            .is_empty());
+    }
+}
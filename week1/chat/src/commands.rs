@@ -0,0 +1,504 @@
+use std::fmt;
+
+use crate::types::ErrorCode;
+
+/// What the user typed into the text box, once slash commands are picked out.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Input {
+    /// An ordinary message. A leading `//` is how to send text that starts with `/`.
+    Text(String),
+    Command(Command),
+}
+
+/// A parsed slash command with its arguments.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Command {
+    Join {
+        room: String,
+    },
+    Leave,
+    Nick {
+        display_name: String,
+    },
+    Me {
+        action: String,
+    },
+    Who,
+    Away,
+    Back,
+    Help {
+        command: Option<String>,
+    },
+    Kick {
+        username: String,
+        reason: Option<String>,
+    },
+    Ban {
+        username: String,
+        duration_secs: Option<u64>,
+        reason: Option<String>,
+    },
+    Unban {
+        username: String,
+    },
+    Mute {
+        username: String,
+        duration_secs: Option<u64>,
+    },
+    Unmute {
+        username: String,
+    },
+    /// Makes the user a moderator, or takes it away again.
+    Mod {
+        username: String,
+        moderator: bool,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum CommandError {
+    Unknown {
+        name: String,
+    },
+    /// The arguments didn't fit, `usage` says what would have.
+    Usage {
+        usage: &'static str,
+    },
+}
+
+impl CommandError {
+    pub fn code(&self) -> ErrorCode {
+        ErrorCode::InvalidCommand
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Unknown { name } => {
+                write!(f, "Unknown command /{}, try /help", name)
+            }
+            CommandError::Usage { usage } => write!(f, "Usage: {}", usage),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+/// Everything after the command name, handed out one typed piece at a time.
+struct Args<'a> {
+    rest: &'a str,
+    usage: &'static str,
+}
+
+impl<'a> Args<'a> {
+    /// The next whitespace separated word.
+    fn word(&mut self) -> Result<&'a str, CommandError> {
+        self.optional_word().ok_or(self.usage())
+    }
+
+    /// The next whitespace separated word, if there is one.
+    fn optional_word(&mut self) -> Option<&'a str> {
+        let rest = self.rest.trim_start();
+        if rest.is_empty() {
+            return None;
+        }
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let (word, rest) = rest.split_at(end);
+        self.rest = rest;
+        Some(word)
+    }
+
+    /// Everything that's left, which has to be something.
+    fn text(&mut self) -> Result<&'a str, CommandError> {
+        let text = self.rest.trim();
+        self.rest = "";
+        if text.is_empty() {
+            return Err(self.usage());
+        }
+        Ok(text)
+    }
+
+    /// Whatever is left, if anything.
+    fn optional_text(&mut self) -> Option<String> {
+        let text = self.rest.trim();
+        self.rest = "";
+        (!text.is_empty()).then(|| text.to_string())
+    }
+
+    /// A duration like `90s`, `10m`, `2h` or `7d` in seconds, if that's what
+    /// comes next. A bare number is minutes. A word that starts with a digit
+    /// but isn't a duration, like `0m` or `5x`, is an error rather than the
+    /// start of whatever follows.
+    fn optional_duration(&mut self) -> Result<Option<u64>, CommandError> {
+        let before = self.rest;
+        match self.optional_word() {
+            Some(word) if word.starts_with(|c: char| c.is_ascii_digit()) => {
+                parse_duration(word).map(Some).ok_or(self.usage())
+            }
+            _ => {
+                self.rest = before;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Fails if anything is left over.
+    fn end(&self) -> Result<(), CommandError> {
+        if self.rest.trim().is_empty() {
+            Ok(())
+        } else {
+            Err(self.usage())
+        }
+    }
+
+    fn usage(&self) -> CommandError {
+        CommandError::Usage { usage: self.usage }
+    }
+}
+
+/// One entry in the command table. To add a command, give it a `Command`
+/// variant, an entry here and an arm in `Router::run_command`.
+struct CommandSpec {
+    name: &'static str,
+    usage: &'static str,
+    summary: &'static str,
+    parse: fn(&mut Args) -> Result<Command, CommandError>,
+}
+
+const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "join",
+        usage: "/join <room>",
+        summary: "Switch to the room with that name",
+        parse: |args| {
+            let room = args.text()?.to_string();
+            Ok(Command::Join { room })
+        },
+    },
+    CommandSpec {
+        name: "leave",
+        usage: "/leave",
+        summary: "Leave the room and give up your membership",
+        parse: |args| args.end().map(|_| Command::Leave),
+    },
+    CommandSpec {
+        name: "nick",
+        usage: "/nick <display name>",
+        summary: "Change the name other people see",
+        parse: |args| {
+            let display_name = args.text()?.to_string();
+            Ok(Command::Nick { display_name })
+        },
+    },
+    CommandSpec {
+        name: "me",
+        usage: "/me <action>",
+        summary: "Say what you're doing, in the third person",
+        parse: |args| {
+            let action = args.text()?.to_string();
+            Ok(Command::Me { action })
+        },
+    },
+    CommandSpec {
+        name: "who",
+        usage: "/who",
+        summary: "List the room's members and who is around",
+        parse: |args| args.end().map(|_| Command::Who),
+    },
+    CommandSpec {
+        name: "away",
+        usage: "/away",
+        summary: "Show as away until you say /back",
+        parse: |args| args.end().map(|_| Command::Away),
+    },
+    CommandSpec {
+        name: "back",
+        usage: "/back",
+        summary: "Show as online again",
+        parse: |args| args.end().map(|_| Command::Back),
+    },
+    CommandSpec {
+        name: "help",
+        usage: "/help [command]",
+        summary: "List the commands, or explain one",
+        parse: |args| {
+            let command = args
+                .optional_word()
+                .map(|name| name.trim_start_matches('/').to_string());
+            args.end()?;
+            Ok(Command::Help { command })
+        },
+    },
+    CommandSpec {
+        name: "kick",
+        usage: "/kick <username> [reason]",
+        summary: "Moderators: take someone out of the room",
+        parse: |args| {
+            let username = args.word()?.to_string();
+            let reason = args.optional_text();
+            Ok(Command::Kick { username, reason })
+        },
+    },
+    CommandSpec {
+        name: "ban",
+        usage: "/ban <username> [duration] [reason]",
+        summary:
+            "Moderators: kick someone and keep them out, for a while like 30m or 7d or for good",
+        parse: |args| {
+            let username = args.word()?.to_string();
+            let duration_secs = args.optional_duration()?;
+            let reason = args.optional_text();
+            Ok(Command::Ban {
+                username,
+                duration_secs,
+                reason,
+            })
+        },
+    },
+    CommandSpec {
+        name: "unban",
+        usage: "/unban <username>",
+        summary: "Moderators: let someone back in",
+        parse: |args| {
+            let username = args.word()?.to_string();
+            args.end()?;
+            Ok(Command::Unban { username })
+        },
+    },
+    CommandSpec {
+        name: "mute",
+        usage: "/mute <username> [duration]",
+        summary: "Moderators: stop someone from sending messages, for a while or until /unmute",
+        parse: |args| {
+            let username = args.word()?.to_string();
+            let duration_secs = args.optional_duration()?;
+            args.end()?;
+            Ok(Command::Mute {
+                username,
+                duration_secs,
+            })
+        },
+    },
+    CommandSpec {
+        name: "unmute",
+        usage: "/unmute <username>",
+        summary: "Moderators: let someone talk again",
+        parse: |args| {
+            let username = args.word()?.to_string();
+            args.end()?;
+            Ok(Command::Unmute { username })
+        },
+    },
+    CommandSpec {
+        name: "mod",
+        usage: "/mod <username>",
+        summary: "Owner: make someone a moderator of the room",
+        parse: |args| {
+            let username = args.word()?.to_string();
+            args.end()?;
+            Ok(Command::Mod {
+                username,
+                moderator: true,
+            })
+        },
+    },
+    CommandSpec {
+        name: "unmod",
+        usage: "/unmod <username>",
+        summary: "Owner: make a moderator a regular member again",
+        parse: |args| {
+            let username = args.word()?.to_string();
+            args.end()?;
+            Ok(Command::Mod {
+                username,
+                moderator: false,
+            })
+        },
+    },
+];
+
+/// Splits a slash command from ordinary text.
+pub(crate) fn parse(text: String) -> Result<Input, CommandError> {
+    if let Some(escaped) = text.strip_prefix("//") {
+        return Ok(Input::Text(format!("/{}", escaped)));
+    }
+    let Some(line) = text.strip_prefix('/') else {
+        return Ok(Input::Text(text));
+    };
+
+    let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let spec = find(name).ok_or_else(|| CommandError::Unknown {
+        name: name.to_string(),
+    })?;
+    let mut args = Args {
+        rest,
+        usage: spec.usage,
+    };
+    (spec.parse)(&mut args).map(Input::Command)
+}
+
+/// The command list, or the details of one command.
+pub(crate) fn help(command: Option<&str>) -> Result<String, CommandError> {
+    match command {
+        Some(name) => {
+            let spec = find(name).ok_or_else(|| CommandError::Unknown {
+                name: name.to_string(),
+            })?;
+            Ok(format!("{} - {}", spec.usage, spec.summary))
+        }
+        None => Ok(COMMANDS
+            .iter()
+            .map(|spec| format!("{} - {}", spec.usage, spec.summary))
+            .collect::<Vec<_>>()
+            .join("\n")),
+    }
+}
+
+/// Seconds in `word`, never 0.
+fn parse_duration(word: &str) -> Option<u64> {
+    let (number, unit) = match word.find(|c: char| !c.is_ascii_digit()) {
+        Some(end) => word.split_at(end),
+        None => (word, "m"),
+    };
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    number
+        .parse::<u64>()
+        .ok()?
+        .checked_mul(scale)
+        .filter(|&secs| secs > 0)
+}
+
+fn find(name: &str) -> Option<&'static CommandSpec> {
+    let name = name.to_lowercase();
+    COMMANDS.iter().find(|spec| spec.name == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(text: &str) -> Command {
+        match parse(text.to_string()) {
+            Ok(Input::Command(command)) => command,
+            other => panic!("{:?} parsed as {:?}", text, other),
+        }
+    }
+
+    fn usage(text: &str) -> &'static str {
+        match parse(text.to_string()) {
+            Err(CommandError::Usage { usage }) => usage,
+            other => panic!("{:?} parsed as {:?}", text, other),
+        }
+    }
+
+    #[test]
+    fn text_is_not_a_command() {
+        assert_eq!(
+            parse("hello".to_string()),
+            Ok(Input::Text("hello".to_string()))
+        );
+        assert_eq!(
+            parse("//join is a command".to_string()),
+            Ok(Input::Text("/join is a command".to_string()))
+        );
+    }
+
+    #[test]
+    fn parses_arguments() {
+        assert_eq!(
+            command("/join  the lobby "),
+            Command::Join {
+                room: "the lobby".to_string()
+            }
+        );
+        assert_eq!(command("/LEAVE"), Command::Leave);
+        assert_eq!(
+            command("/help /ban"),
+            Command::Help {
+                command: Some("ban".to_string())
+            }
+        );
+        assert_eq!(
+            command("/kick bob too loud"),
+            Command::Kick {
+                username: "bob".to_string(),
+                reason: Some("too loud".to_string())
+            }
+        );
+        assert_eq!(
+            command("/unmod bob"),
+            Command::Mod {
+                username: "bob".to_string(),
+                moderator: false
+            }
+        );
+    }
+
+    #[test]
+    fn takes_an_optional_duration() {
+        assert_eq!(
+            command("/ban bob 2h spam"),
+            Command::Ban {
+                username: "bob".to_string(),
+                duration_secs: Some(2 * 60 * 60),
+                reason: Some("spam".to_string())
+            }
+        );
+        assert_eq!(
+            command("/ban bob spam"),
+            Command::Ban {
+                username: "bob".to_string(),
+                duration_secs: None,
+                reason: Some("spam".to_string())
+            }
+        );
+        assert_eq!(
+            command("/mute bob 5"),
+            Command::Mute {
+                username: "bob".to_string(),
+                duration_secs: Some(5 * 60)
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert_eq!(usage("/join"), "/join <room>");
+        assert_eq!(usage("/leave now"), "/leave");
+        assert_eq!(usage("/mute bob 10m later"), "/mute <username> [duration]");
+        assert_eq!(usage("/mute bob 0m"), "/mute <username> [duration]");
+        assert_eq!(
+            usage("/ban bob 3x spam"),
+            "/ban <username> [duration] [reason]"
+        );
+        assert_eq!(
+            parse("/dance".to_string()),
+            Err(CommandError::Unknown {
+                name: "dance".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90s"), Some(90));
+        assert_eq!(parse_duration("10m"), Some(600));
+        assert_eq!(parse_duration("10"), Some(600));
+        assert_eq!(parse_duration("2h"), Some(7200));
+        assert_eq!(parse_duration("7d"), Some(7 * 24 * 60 * 60));
+    }
+
+    #[test]
+    fn rejects_bad_durations() {
+        for word in ["0", "0s", "0d", "10x", "m", "1.5h", "99999999999999999999d"] {
+            assert_eq!(parse_duration(word), None, "{:?}", word);
+        }
+    }
+}
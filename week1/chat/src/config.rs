@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::env;
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::NaiveTime;
+use log::warn;
+
+use crate::bots::{self, BotSpec};
+use crate::filters::{self, RoomFilters, DEFAULT_FILTERS, DEFAULT_PROFANITY};
+use crate::http_api;
+use crate::outbound_queue::{QueueConfig, SlowConsumerPolicy};
+use crate::rate_limit::RateLimits;
+use crate::reactions::ReactionSet;
+
+const ADDRESS: &str = "127.0.0.1:3030";
+const HTTP_ADDRESS: &str = "127.0.0.1:3031";
+const DATABASE: &str = "chat.db";
+const STANDUP_AT: &str = "09:30";
+
+/// Server settings, read once at startup from `CHAT_*` environment variables.
+#[derive(Debug, Clone)]
+pub(crate) struct ServerConfig {
+    pub address: String,
+    /// Where the HTTP API listens.
+    pub http_address: String,
+    /// SQLite file to keep state in, or `:memory:` to keep nothing.
+    pub database: String,
+    pub outbound: QueueConfig,
+    /// Messages per history page, also what a room sends on join.
+    pub history_page: usize,
+    /// Largest page a client may ask for.
+    pub max_history_page: usize,
+    /// How long a dropped session can still be resumed.
+    pub resume_ttl: Duration,
+    /// How often each connection is pinged.
+    pub ping_interval: Duration,
+    /// How long a connection may stay silent after a ping before it is dropped.
+    pub pong_timeout: Duration,
+    /// How long a connection may take to log in before it is dropped.
+    pub login_timeout: Duration,
+    /// At most one typing event per session per room is forwarded in this window.
+    pub typing_throttle: Duration,
+    /// How long clients should show a typing event for unless another one comes in.
+    pub typing_ttl: Duration,
+    /// What people may react to messages with.
+    pub reactions: ReactionSet,
+    /// Token buckets for each session and each user.
+    pub rate_limits: RateLimits,
+    /// What every message goes through before it is stored, per room.
+    pub filters: RoomFilters,
+    /// Bots the router hosts, and the rooms each one sits in.
+    pub bots: Vec<BotSpec>,
+    /// HTTP API bearer tokens, each mapped to the username it acts as.
+    pub api_tokens: HashMap<String, String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            address: ADDRESS.to_string(),
+            http_address: HTTP_ADDRESS.to_string(),
+            database: DATABASE.to_string(),
+            outbound: QueueConfig::default(),
+            history_page: 50,
+            max_history_page: 500,
+            resume_ttl: Duration::from_secs(300),
+            ping_interval: Duration::from_secs(20),
+            pong_timeout: Duration::from_secs(10),
+            login_timeout: Duration::from_secs(30),
+            typing_throttle: Duration::from_secs(3),
+            typing_ttl: Duration::from_secs(6),
+            reactions: ReactionSet::default(),
+            rate_limits: RateLimits::default(),
+            filters: RoomFilters::default(),
+            bots: Vec::new(),
+            api_tokens: HashMap::new(),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn from_env() -> Self {
+        let default = ServerConfig::default();
+
+        let policy = match env::var("CHAT_SLOW_CONSUMER").as_deref() {
+            Ok("disconnect") => SlowConsumerPolicy::Disconnect {
+                max_missed: env_or("CHAT_MAX_MISSED", 256),
+            },
+            Ok("drop_oldest") | Err(_) => SlowConsumerPolicy::DropOldest,
+            Ok(other) => {
+                warn!("Unknown CHAT_SLOW_CONSUMER {:?}, using drop_oldest", other);
+                SlowConsumerPolicy::DropOldest
+            }
+        };
+
+        let profanity =
+            filters::words(&env::var("CHAT_PROFANITY").unwrap_or(DEFAULT_PROFANITY.to_string()));
+        let filters = RoomFilters::parse(
+            &env::var("CHAT_FILTERS").unwrap_or(DEFAULT_FILTERS.to_string()),
+            &env::var("CHAT_ROOM_FILTERS").unwrap_or_default(),
+            &profanity,
+        )
+        .unwrap_or_else(|e| {
+            warn!("Ignoring invalid message filters, {}", e);
+            default.filters.clone()
+        });
+
+        let standup_at = env_or(
+            "CHAT_STANDUP_AT",
+            NaiveTime::parse_from_str(STANDUP_AT, "%H:%M").expect("valid default"),
+        );
+        let bots = bots::parse_specs(&env::var("CHAT_BOTS").unwrap_or_default(), standup_at)
+            .unwrap_or_else(|e| {
+                warn!("Ignoring invalid CHAT_BOTS, {}", e);
+                default.bots.clone()
+            });
+
+        let api_tokens = http_api::parse_tokens(&env::var("CHAT_API_TOKENS").unwrap_or_default())
+            .unwrap_or_else(|e| {
+                warn!("Ignoring invalid CHAT_API_TOKENS, {}", e);
+                default.api_tokens.clone()
+            });
+
+        ServerConfig {
+            address: env_or("CHAT_ADDRESS", default.address),
+            http_address: env_or("CHAT_HTTP_ADDRESS", default.http_address),
+            database: env_or("CHAT_DATABASE", default.database),
+            outbound: QueueConfig {
+                capacity: env_or("CHAT_QUEUE_CAPACITY", default.outbound.capacity),
+                policy,
+            },
+            history_page: env_nonzero("CHAT_HISTORY_PAGE", default.history_page),
+            max_history_page: env_nonzero("CHAT_MAX_HISTORY_PAGE", default.max_history_page),
+            resume_ttl: env_secs("CHAT_RESUME_TTL_SECS", default.resume_ttl),
+            ping_interval: env_nonzero_secs("CHAT_PING_INTERVAL_SECS", default.ping_interval),
+            pong_timeout: env_nonzero_secs("CHAT_PONG_TIMEOUT_SECS", default.pong_timeout),
+            login_timeout: env_nonzero_secs("CHAT_LOGIN_TIMEOUT_SECS", default.login_timeout),
+            typing_throttle: env_secs("CHAT_TYPING_THROTTLE_SECS", default.typing_throttle),
+            typing_ttl: env_secs("CHAT_TYPING_TTL_SECS", default.typing_ttl),
+            reactions: env::var("CHAT_REACTIONS")
+                .map(|spec| ReactionSet::parse(&spec))
+                .unwrap_or(default.reactions),
+            rate_limits: RateLimits {
+                messages: env_or("CHAT_RATE_MESSAGES", default.rate_limits.messages),
+                joins: env_or("CHAT_RATE_JOINS", default.rate_limits.joins),
+                history: env_or("CHAT_RATE_HISTORY", default.rate_limits.history),
+                activity: env_or("CHAT_RATE_ACTIVITY", default.rate_limits.activity),
+                auth: env_or("CHAT_RATE_AUTH", default.rate_limits.auth),
+                strikes: env_or("CHAT_RATE_STRIKES", default.rate_limits.strikes),
+            },
+            filters,
+            bots,
+            api_tokens,
+        }
+    }
+}
+
+/// Reads and parses an environment variable, falling back to `default` if it
+/// is unset or doesn't parse.
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            warn!("Ignoring invalid {}={:?}", name, value);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+/// `env_or` for counts where zero makes no sense.
+fn env_nonzero(name: &str, default: usize) -> usize {
+    match NonZeroUsize::new(default) {
+        Some(default) => env_or(name, default).get(),
+        None => default,
+    }
+}
+
+fn env_secs(name: &str, default: Duration) -> Duration {
+    Duration::from_secs(env_or(name, default.as_secs()))
+}
+
+/// `env_secs` for timers that can't run with a zero period.
+fn env_nonzero_secs(name: &str, default: Duration) -> Duration {
+    match NonZeroU64::new(default.as_secs()) {
+        Some(default) => Duration::from_secs(env_or(name, default).get()),
+        None => default,
+    }
+}
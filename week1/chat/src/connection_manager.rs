@@ -1,18 +1,356 @@
 use std::net::SocketAddr;
-use tokio::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
 
+use futures::stream::{SplitSink, SplitStream};
+use futures::{SinkExt, StreamExt};
+use log::{debug, info};
+use tokio::net::TcpStream;
 use tokio::sync::mpsc::Sender;
-use tokio::sync::oneshot::Receiver;
+use tokio::time::{self, Instant, MissedTickBehavior};
+use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio_tungstenite::{accept_async, WebSocketStream};
+use uuid::Uuid;
+
+use crate::config::ServerConfig;
+use crate::outbound_queue::{self, QueueReceiver};
+use crate::types::{ClientMessage, ClientReply, Error, ErrorCode, RouterMessage};
 
-use crate::types::{ClientReply, RouterMessage};
+type WsSender = SplitSink<WebSocketStream<TcpStream>, Message>;
+type WsReceiver = SplitStream<WebSocketStream<TcpStream>>;
 
-pub(crate) fn handle_connection(
+pub(crate) async fn handle_connection(
     stream: TcpStream,
     addr: SocketAddr,
-    router_tx_clone: Sender<RouterMessage>,
-    user_rx: Receiver<ClientReply>,
-) -> Result<(), std::io::Error> {
-    todo!();
+    router_tx: Sender<RouterMessage>,
+    config: Arc<ServerConfig>,
+) -> Result<(), Error> {
+    let ws_stream = accept_async(stream).await?;
+
+    let session_id = Uuid::new_v4().to_u128_le();
+    let (reply_tx, mut reply_rx) = outbound_queue::channel(config.outbound);
+
+    router_tx
+        .send(RouterMessage::Connect {
+            session_id,
+            reply_tx,
+            addr: Some(addr.ip()),
+        })
+        .await?;
+
+    info!(
+        "New WebSocket connection from {} as session {}",
+        addr, session_id
+    );
+
+    let (ws_tx, ws_rx) = ws_stream.split();
+    let result = run_session(session_id, ws_tx, ws_rx, &router_tx, &mut reply_rx, &config).await;
+
+    // Whatever happened to the socket, the router has to forget this session,
+    // otherwise it would sit in its room forever.
+    let _ = router_tx
+        .send(RouterMessage::Disconnect { session_id })
+        .await;
+    let stats = reply_rx.stats();
+    info!(
+        "Session {} from {} closed, {} replies delivered, {} dropped, peak queue depth {}",
+        session_id, addr, stats.delivered, stats.dropped, stats.max_depth
+    );
+
+    result
+}
+
+/// Pumps frames from the socket to the router and replies from the router back
+/// to the socket until either side hangs up, the client stops answering pings
+/// or it takes too long to log in.
+async fn run_session(
+    session_id: u128,
+    mut ws_tx: WsSender,
+    mut ws_rx: WsReceiver,
+    router_tx: &Sender<RouterMessage>,
+    reply_rx: &mut QueueReceiver,
+    config: &ServerConfig,
+) -> Result<(), Error> {
+    let mut ping = time::interval_at(Instant::now() + config.ping_interval, config.ping_interval);
+    ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    // Armed whenever a ping goes out, pushed back by anything the client sends.
+    let pong_deadline = time::sleep(Duration::MAX);
+    tokio::pin!(pong_deadline);
+    let mut awaiting_pong = false;
+
+    let login_deadline = time::sleep(config.login_timeout);
+    tokio::pin!(login_deadline);
+    let mut logged_in = false;
+
+    loop {
+        tokio::select! {
+            frame = ws_rx.next() => {
+                let Some(frame) = frame else {
+                    break;
+                };
+                awaiting_pong = false;
+
+                match frame? {
+                    Message::Text(text) => match serde_json::from_str::<ClientMessage>(&text) {
+                        Ok(msg) => router_tx.send(into_router_message(msg, session_id)).await?,
+                        Err(e) => {
+                            debug!("Session {} sent malformed JSON: {}", session_id, e);
+                            send_reply(&mut ws_tx, &protocol_err(ErrorCode::MalformedMessage, format!("Malformed message: {}", e)), config.pong_timeout).await?;
+                        }
+                    },
+                    Message::Binary(_) => {
+                        send_reply(&mut ws_tx, &protocol_err(ErrorCode::UnsupportedFrame, "Binary frames are not supported".to_string()), config.pong_timeout).await?;
+                    }
+                    Message::Close(frame) => {
+                        debug!("Session {} sent close: {:?}", session_id, frame);
+                        break;
+                    }
+                    // tungstenite answers pings on its own.
+                    Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => {}
+                }
+            }
+            reply = reply_rx.recv() => {
+                let Some(reply) = reply else {
+                    // The router dropped us.
+                    break;
+                };
+                if matches!(reply, ClientReply::LoggedIn { .. } | ClientReply::Resumed { .. }) {
+                    logged_in = true;
+                }
+                send_reply(&mut ws_tx, &reply, config.pong_timeout).await?;
+            }
+            _ = ping.tick() => {
+                send_frame(&mut ws_tx, Message::Ping(Default::default()), config.pong_timeout).await?;
+                if !awaiting_pong {
+                    awaiting_pong = true;
+                    pong_deadline.as_mut().reset(Instant::now() + config.pong_timeout);
+                }
+            }
+            _ = &mut pong_deadline, if awaiting_pong => {
+                info!("Session {} missed its pong deadline, dropping it", session_id);
+                break;
+            }
+            _ = &mut login_deadline, if !logged_in => {
+                info!("Session {} didn't log in within {:?}, dropping it", session_id, config.login_timeout);
+                let _ = send_reply(&mut ws_tx, &protocol_err(ErrorCode::LoginTimeout, "Took too long to log in".to_string()), config.pong_timeout).await;
+                break;
+            }
+        }
+    }
+
+    let _ = time::timeout(config.pong_timeout, ws_tx.close()).await;
+    Ok(())
+}
+
+fn into_router_message(msg: ClientMessage, session_id: u128) -> RouterMessage {
+    match msg {
+        ClientMessage::Register {
+            username,
+            display_name,
+            password,
+        } => RouterMessage::Register {
+            username,
+            display_name,
+            password,
+            session_id,
+        },
+        ClientMessage::Login { username, password } => RouterMessage::Login {
+            username,
+            password,
+            session_id,
+        },
+        ClientMessage::Resume { token, last_seen } => RouterMessage::Resume {
+            token,
+            last_seen,
+            session_id,
+        },
+        ClientMessage::JoinRoom { room_id } => RouterMessage::JoinRoom {
+            room_id,
+            session_id,
+        },
+        ClientMessage::SendMessage {
+            room_id,
+            message,
+            parent_id,
+        } => RouterMessage::SendMessage {
+            room_id,
+            message,
+            parent_id,
+            session_id,
+        },
+        ClientMessage::LeaveRoom => RouterMessage::LeaveRoom { session_id },
+        ClientMessage::FetchHistory {
+            room_id,
+            cursor,
+            limit,
+        } => RouterMessage::FetchHistory {
+            room_id,
+            cursor,
+            limit,
+            session_id,
+        },
+        ClientMessage::CreateRoom { name, capacity } => RouterMessage::CreateRoom {
+            name,
+            capacity,
+            session_id,
+        },
+        ClientMessage::ListRooms => RouterMessage::ListRooms { session_id },
+        ClientMessage::RenameRoom { room_id, name } => RouterMessage::RenameRoom {
+            room_id,
+            name,
+            session_id,
+        },
+        ClientMessage::DeleteRoom { room_id } => RouterMessage::DeleteRoom {
+            room_id,
+            session_id,
+        },
+        ClientMessage::SendDirect { to_user_id, text } => RouterMessage::SendDirect {
+            to_user_id,
+            text,
+            session_id,
+        },
+        ClientMessage::FetchDirectHistory {
+            user_id,
+            cursor,
+            limit,
+        } => RouterMessage::FetchDirectHistory {
+            user_id,
+            cursor,
+            limit,
+            session_id,
+        },
+        ClientMessage::SetAway { away } => RouterMessage::SetAway { away, session_id },
+        ClientMessage::Typing { room_id } => RouterMessage::Typing {
+            room_id,
+            session_id,
+        },
+        ClientMessage::MarkRead { room_id, up_to } => RouterMessage::MarkRead {
+            room_id,
+            up_to,
+            session_id,
+        },
+        ClientMessage::MyRooms => RouterMessage::MyRooms { session_id },
+        ClientMessage::EditMessage {
+            room_id,
+            message_id,
+            text,
+        } => RouterMessage::EditMessage {
+            room_id,
+            message_id,
+            text,
+            session_id,
+        },
+        ClientMessage::DeleteMessage {
+            room_id,
+            message_id,
+        } => RouterMessage::DeleteMessage {
+            room_id,
+            message_id,
+            session_id,
+        },
+        ClientMessage::FetchRevisions {
+            room_id,
+            message_id,
+        } => RouterMessage::FetchRevisions {
+            room_id,
+            message_id,
+            session_id,
+        },
+        ClientMessage::React { message_id, emoji } => RouterMessage::React {
+            message_id,
+            emoji,
+            session_id,
+        },
+        ClientMessage::Unreact { message_id, emoji } => RouterMessage::Unreact {
+            message_id,
+            emoji,
+            session_id,
+        },
+        ClientMessage::FetchThread {
+            room_id,
+            root_id,
+            cursor,
+            limit,
+        } => RouterMessage::FetchThread {
+            room_id,
+            root_id,
+            cursor,
+            limit,
+            session_id,
+        },
+        ClientMessage::SetRole {
+            room_id,
+            user_id,
+            role,
+        } => RouterMessage::SetRole {
+            room_id,
+            user_id,
+            role,
+            session_id,
+        },
+        ClientMessage::Kick {
+            room_id,
+            user_id,
+            reason,
+        } => RouterMessage::Kick {
+            room_id,
+            user_id,
+            reason,
+            session_id,
+        },
+        ClientMessage::Ban {
+            room_id,
+            user_id,
+            duration_secs,
+            reason,
+        } => RouterMessage::Ban {
+            room_id,
+            user_id,
+            duration_secs,
+            reason,
+            session_id,
+        },
+        ClientMessage::Unban { room_id, user_id } => RouterMessage::Unban {
+            room_id,
+            user_id,
+            session_id,
+        },
+        ClientMessage::Mute {
+            room_id,
+            user_id,
+            duration_secs,
+        } => RouterMessage::Mute {
+            room_id,
+            user_id,
+            duration_secs,
+            session_id,
+        },
+        ClientMessage::Unmute { room_id, user_id } => RouterMessage::Unmute {
+            room_id,
+            user_id,
+            session_id,
+        },
+    }
+}
+
+fn protocol_err(code: ErrorCode, message: String) -> ClientReply {
+    ClientReply::Err {
+        code,
+        message: Some(message),
+        user_id: None,
+        retry_after_ms: None,
+    }
+}
+
+async fn send_reply(ws_tx: &mut WsSender, reply: &ClientReply, limit: Duration) -> Result<(), Error> {
+    let json = serde_json::to_string(reply)?;
+    send_frame(ws_tx, Message::Text(json.into()), limit).await
+}
 
+/// Gives up on a write the peer hasn't taken within `limit`. A write stuck on
+/// a dead peer would otherwise keep the deadlines in `run_session` from firing.
+async fn send_frame(ws_tx: &mut WsSender, frame: Message, limit: Duration) -> Result<(), Error> {
+    time::timeout(limit, ws_tx.send(frame)).await??;
     Ok(())
 }
@@ -0,0 +1,48 @@
+use std::collections::HashMap;
+
+use chrono::Utc;
+use uuid::Uuid;
+
+use crate::types::{DirectMessage, Pair, Seq};
+
+/// Hands out sequence numbers for direct messages, one counter per pair of users.
+#[derive(Default)]
+pub(crate) struct DirectManager {
+    last_seq: HashMap<Pair, Seq>,
+}
+
+impl DirectManager {
+    pub fn restore(&mut self, pair: Pair, last_seq: Seq) {
+        self.last_seq.insert(pair, last_seq);
+    }
+
+    /// Builds the next message between the two users. Like rooms, the
+    /// sequence number is only taken by `commit_message` once it is stored.
+    pub fn next_message(
+        &self,
+        text: String,
+        from: u128,
+        to: u128,
+        username: String,
+    ) -> DirectMessage {
+        let last_seq = self
+            .last_seq
+            .get(&Pair::new(from, to))
+            .copied()
+            .unwrap_or(0);
+        DirectMessage::new(
+            Uuid::new_v4().to_u128_le(),
+            last_seq + 1,
+            Utc::now(),
+            text,
+            from,
+            to,
+            username,
+        )
+    }
+
+    pub fn commit_message(&mut self, message: &DirectMessage) {
+        let last_seq = self.last_seq.entry(message.pair()).or_default();
+        *last_seq = (*last_seq).max(message.seq());
+    }
+}
@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use regex::Regex;
+
+use crate::types::ErrorCode;
+
+/// Used when `CHAT_FILTERS` isn't set.
+pub(crate) const DEFAULT_FILTERS: &str = "max_length=4000,secrets";
+
+/// Used by `profanity` when `CHAT_PROFANITY` isn't set.
+pub(crate) const DEFAULT_PROFANITY: &str = "damn,shit,fuck,bitch,bastard,asshole";
+
+/// What a filter made of a message.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    Accept,
+    Rewrite(String),
+    /// The message isn't sent, the reason goes back to the sender.
+    Reject(String),
+}
+
+/// One policy check on incoming text. To add one, implement this and give it
+/// a name in `FilterChain::parse`.
+pub(crate) trait MessageFilter: fmt::Debug + Send + Sync {
+    fn check(&self, text: &str) -> Verdict;
+}
+
+#[derive(Debug)]
+pub(crate) struct MaxLength {
+    pub max: usize,
+}
+
+impl MessageFilter for MaxLength {
+    fn check(&self, text: &str) -> Verdict {
+        if text.chars().count() > self.max {
+            Verdict::Reject(format!(
+                "Messages can be at most {} characters long",
+                self.max
+            ))
+        } else {
+            Verdict::Accept
+        }
+    }
+}
+
+/// Replaces listed words with asterisks, whole words only and ignoring case.
+#[derive(Debug)]
+pub(crate) struct ProfanityMask {
+    words: Option<Regex>,
+}
+
+impl ProfanityMask {
+    pub fn new(words: &[String]) -> Self {
+        let alternatives: Vec<String> = words
+            .iter()
+            .map(|word| word.trim())
+            .filter(|word| !word.is_empty())
+            .map(regex::escape)
+            .collect();
+        let words = (!alternatives.is_empty()).then(|| {
+            Regex::new(&format!(r"(?i)\b(?:{})\b", alternatives.join("|")))
+                .expect("escaped words always make a valid pattern")
+        });
+        ProfanityMask { words }
+    }
+}
+
+impl MessageFilter for ProfanityMask {
+    fn check(&self, text: &str) -> Verdict {
+        let Some(words) = &self.words else {
+            return Verdict::Accept;
+        };
+        if !words.is_match(text) {
+            return Verdict::Accept;
+        }
+        let masked = words.replace_all(text, |caps: &regex::Captures| {
+            "*".repeat(caps[0].chars().count())
+        });
+        Verdict::Rewrite(masked.into_owned())
+    }
+}
+
+/// Turns away anything that looks like a credential someone pasted by accident.
+#[derive(Debug)]
+pub(crate) struct SecretBlock {
+    patterns: Vec<(&'static str, Regex)>,
+}
+
+impl Default for SecretBlock {
+    fn default() -> Self {
+        let patterns = [
+            ("an AWS access key", r"\b(?:AKIA|ASIA)[0-9A-Z]{16}\b"),
+            ("a GitHub token", r"\bgh[pousr]_[A-Za-z0-9]{36,}\b"),
+            ("a Slack token", r"\bxox[abprs]-[A-Za-z0-9-]{10,}"),
+            ("an API key", r"\bsk-[A-Za-z0-9_-]{20,}"),
+            ("a private key", r"-----BEGIN [A-Z ]*PRIVATE KEY-----"),
+        ];
+        SecretBlock {
+            patterns: patterns
+                .into_iter()
+                .map(|(what, pattern)| {
+                    (
+                        what,
+                        Regex::new(pattern).expect("built in patterns are valid"),
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+impl MessageFilter for SecretBlock {
+    fn check(&self, text: &str) -> Verdict {
+        match self
+            .patterns
+            .iter()
+            .find(|(_, pattern)| pattern.is_match(text))
+        {
+            Some((what, _)) => {
+                Verdict::Reject(format!("That looks like {}, so it wasn't sent", what))
+            }
+            None => Verdict::Accept,
+        }
+    }
+}
+
+/// Takes out anything that looks like a URL.
+#[derive(Debug)]
+pub(crate) struct StripLinks {
+    links: Regex,
+}
+
+impl Default for StripLinks {
+    fn default() -> Self {
+        StripLinks {
+            links: Regex::new(r"(?i)\b(?:https?://|www\.)\S+").expect("valid pattern"),
+        }
+    }
+}
+
+impl MessageFilter for StripLinks {
+    fn check(&self, text: &str) -> Verdict {
+        if !self.links.is_match(text) {
+            return Verdict::Accept;
+        }
+        Verdict::Rewrite(self.links.replace_all(text, "[link removed]").into_owned())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct FilterError {
+    pub reason: String,
+}
+
+impl FilterError {
+    pub fn code(&self) -> ErrorCode {
+        ErrorCode::MessageRejected
+    }
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+impl std::error::Error for FilterError {}
+
+/// Filters run in order, each one seeing whatever the one before let through.
+#[derive(Debug, Default)]
+pub(crate) struct FilterChain {
+    filters: Vec<Box<dyn MessageFilter>>,
+}
+
+impl FilterChain {
+    /// Reads a comma separated list like `max_length=500,profanity,secrets,strip_links`.
+    pub fn parse(spec: &str, profanity: &[String]) -> Result<Self, String> {
+        let mut chain = FilterChain::default();
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (name, arg) = match entry.split_once('=') {
+                Some((name, arg)) => (name.trim(), Some(arg.trim())),
+                None => (entry, None),
+            };
+            let filter: Box<dyn MessageFilter> = match (name, arg) {
+                ("max_length", Some(max)) => Box::new(MaxLength {
+                    max: max
+                        .parse()
+                        .map_err(|_| format!("max_length needs a number, got {:?}", max))?,
+                }),
+                ("profanity", None) => Box::new(ProfanityMask::new(profanity)),
+                ("secrets", None) => Box::new(SecretBlock::default()),
+                ("strip_links", None) => Box::new(StripLinks::default()),
+                _ => return Err(format!("unknown message filter {:?}", entry)),
+            };
+            chain.filters.push(filter);
+        }
+        Ok(chain)
+    }
+
+    pub fn run(&self, text: String) -> Result<String, FilterError> {
+        let mut text = text;
+        for filter in &self.filters {
+            match filter.check(&text) {
+                Verdict::Accept => {}
+                Verdict::Rewrite(rewritten) => text = rewritten,
+                Verdict::Reject(reason) => return Err(FilterError { reason }),
+            }
+        }
+        Ok(text)
+    }
+}
+
+/// The chain each room runs. Rooms without one of their own get `default`,
+/// a room's own chain replaces it rather than adding to it.
+#[derive(Debug, Clone)]
+pub(crate) struct RoomFilters {
+    default: Arc<FilterChain>,
+    /// Keyed by room id, which unlike the name never changes.
+    rooms: HashMap<u128, Arc<FilterChain>>,
+}
+
+impl Default for RoomFilters {
+    fn default() -> Self {
+        let profanity = words(DEFAULT_PROFANITY);
+        RoomFilters {
+            default: Arc::new(
+                FilterChain::parse(DEFAULT_FILTERS, &profanity).expect("default filters parse"),
+            ),
+            rooms: HashMap::new(),
+        }
+    }
+}
+
+impl RoomFilters {
+    /// `rooms` is a semicolon separated list of `room id:chain`, for example
+    /// `1234:strip_links,max_length=500;5678:profanity`.
+    pub fn parse(default: &str, rooms: &str, profanity: &[String]) -> Result<Self, String> {
+        let mut filters = RoomFilters {
+            default: Arc::new(FilterChain::parse(default, profanity)?),
+            rooms: HashMap::new(),
+        };
+        for entry in rooms.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+            let (room_id, spec) = entry
+                .split_once(':')
+                .ok_or_else(|| format!("expected room:filters, got {:?}", entry))?;
+            let room_id = room_id
+                .trim()
+                .parse()
+                .map_err(|_| format!("expected a room id, got {:?}", room_id))?;
+            filters
+                .rooms
+                .insert(room_id, Arc::new(FilterChain::parse(spec, profanity)?));
+        }
+        Ok(filters)
+    }
+
+    pub fn default_chain(&self) -> &FilterChain {
+        &self.default
+    }
+
+    /// Rooms with a chain of their own.
+    pub fn room_ids(&self) -> impl Iterator<Item = u128> + '_ {
+        self.rooms.keys().copied()
+    }
+
+    pub fn for_room(&self, room_id: u128) -> &FilterChain {
+        self.rooms.get(&room_id).unwrap_or(&self.default)
+    }
+}
+
+/// Splits a comma separated word list.
+pub(crate) fn words(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|word| !word.is_empty())
+        .map(str::to_string)
+        .collect()
+}
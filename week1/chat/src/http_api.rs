@@ -0,0 +1,574 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::debug;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::Sender;
+use tokio::time;
+use uuid::Uuid;
+use warp::filters::body::BodyDeserializeError;
+use warp::http::header::RETRY_AFTER;
+use warp::http::StatusCode;
+use warp::reject::InvalidQuery;
+use warp::reply::{self, Response};
+use warp::{Filter, Rejection, Reply};
+
+use crate::config::ServerConfig;
+use crate::outbound_queue;
+use crate::types::{ClientReply, Cursor, ErrorCode, RouterMessage, Seq, Time, UserTextMessage};
+
+/// How long a request waits on the router before giving up.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest request body accepted.
+const MAX_BODY: u64 = 64 * 1024;
+
+/// Reads a comma separated list of `username=token` pairs into a map from
+/// token to username.
+pub(crate) fn parse_tokens(spec: &str) -> Result<HashMap<String, String>, String> {
+    let mut tokens = HashMap::new();
+    for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (username, token) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("expected username=token, got {:?}", entry))?;
+        let (username, token) = (username.trim(), token.trim());
+        if username.is_empty() || token.is_empty() {
+            return Err(format!("expected username=token, got {:?}", entry));
+        }
+        tokens.insert(token.to_string(), username.to_string());
+    }
+    Ok(tokens)
+}
+
+#[derive(Deserialize)]
+struct NewRoom {
+    name: String,
+    capacity: u32,
+}
+
+#[derive(Deserialize)]
+struct NewMessage {
+    text: String,
+}
+
+/// At most one of `before` and `after`, neither for the newest page.
+#[derive(Deserialize)]
+struct HistoryQuery {
+    before: Option<Seq>,
+    after: Option<Seq>,
+    limit: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct HistoryPage {
+    room_id: u128,
+    messages: Vec<Arc<UserTextMessage>>,
+    next: Option<Cursor>,
+}
+
+#[derive(Serialize)]
+struct Posted {
+    room_id: u128,
+    message_id: u128,
+    seq: Seq,
+    time: Time,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: ErrorCode,
+    message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after_ms: Option<u64>,
+}
+
+/// What every handler needs to reach the router.
+#[derive(Clone)]
+struct Api {
+    router_tx: Sender<RouterMessage>,
+    config: Arc<ServerConfig>,
+    /// Where the request came from.
+    addr: Option<IpAddr>,
+}
+
+/// JSON over HTTP next to the WebSocket, for tools that would rather not
+/// hold a connection open. Every request needs `Authorization: Bearer <token>`
+/// with a token from `CHAT_API_TOKENS`, and acts as that token's user.
+///
+/// - `GET /api/rooms`
+/// - `POST /api/rooms` with `{"name": ..., "capacity": ...}`
+/// - `GET /api/rooms/<id>/messages?before=<seq>&limit=<n>`, or `after=<seq>`
+/// - `POST /api/rooms/<id>/messages` with `{"text": ...}`
+/// - `GET /api/users/<username>`
+pub(crate) fn routes(
+    router_tx: Sender<RouterMessage>,
+    config: Arc<ServerConfig>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let api = Api {
+        router_tx,
+        config,
+        addr: None,
+    };
+    let api = warp::addr::remote()
+        .map(move |addr: Option<SocketAddr>| Api {
+            addr: addr.map(|addr| addr.ip()),
+            ..api.clone()
+        })
+        .and(warp::header::optional::<String>("authorization"));
+
+    let list_rooms = warp::path!("api" / "rooms")
+        .and(warp::get())
+        .and(api.clone())
+        .then(|api: Api, auth: Option<String>| async move {
+            api.call(auth, |session_id| RouterMessage::ListRooms { session_id })
+                .await
+        });
+
+    let create_room = warp::path!("api" / "rooms")
+        .and(warp::post())
+        .and(api.clone())
+        .and(json_body())
+        .then(|api: Api, auth: Option<String>, room: NewRoom| async move {
+            api.call(auth, |session_id| RouterMessage::CreateRoom {
+                name: room.name,
+                capacity: room.capacity,
+                session_id,
+            })
+            .await
+        });
+
+    let history = warp::path!("api" / "rooms" / u128 / "messages")
+        .and(warp::get())
+        .and(api.clone())
+        .and(warp::query::<HistoryQuery>())
+        .then(
+            |room_id: u128, api: Api, auth: Option<String>, query: HistoryQuery| async move {
+                let cursor = match (query.before, query.after) {
+                    (Some(seq), None) => Some(Cursor::Before(seq)),
+                    (None, Some(seq)) => Some(Cursor::After(seq)),
+                    (None, None) => None,
+                    (Some(_), Some(_)) => {
+                        return error(
+                            StatusCode::BAD_REQUEST,
+                            ErrorCode::MalformedMessage,
+                            "Pass before or after, not both".to_string(),
+                        )
+                    }
+                };
+                api.call(auth, |session_id| RouterMessage::ReadHistory {
+                    room_id,
+                    cursor,
+                    limit: query.limit,
+                    session_id,
+                })
+                .await
+            },
+        );
+
+    let post_message = warp::path!("api" / "rooms" / u128 / "messages")
+        .and(warp::post())
+        .and(api.clone())
+        .and(json_body())
+        .then(
+            |room_id: u128, api: Api, auth: Option<String>, message: NewMessage| async move {
+                api.call(auth, |session_id| RouterMessage::PostMessage {
+                    room_id,
+                    message: message.text,
+                    session_id,
+                })
+                .await
+            },
+        );
+
+    let find_user = warp::path!("api" / "users" / String)
+        .and(warp::get())
+        .and(api)
+        .then(
+            |username: String, api: Api, auth: Option<String>| async move {
+                api.call(auth, |session_id| RouterMessage::FindUser {
+                    username,
+                    session_id,
+                })
+                .await
+            },
+        );
+
+    list_rooms
+        .or(create_room)
+        .unify()
+        .or(history)
+        .unify()
+        .or(post_message)
+        .unify()
+        .or(find_user)
+        .unify()
+        .recover(bad_request)
+        .unify()
+}
+
+fn json_body<T: DeserializeOwned + Send>() -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+{
+    warp::body::content_length_limit(MAX_BODY).and(warp::body::json())
+}
+
+impl Api {
+    /// Runs the request as a session of its own, logged in by the bearer
+    /// token, and turns whatever the router answers into the response.
+    async fn call(
+        &self,
+        auth: Option<String>,
+        request: impl FnOnce(u128) -> RouterMessage,
+    ) -> Response {
+        let Some(token) = auth
+            .as_deref()
+            .and_then(|auth| auth.strip_prefix("Bearer "))
+        else {
+            return error(
+                StatusCode::UNAUTHORIZED,
+                ErrorCode::InvalidToken,
+                "Expected Authorization: Bearer <token>".to_string(),
+            );
+        };
+
+        let session_id = Uuid::new_v4().to_u128_le();
+        let (reply_tx, mut reply_rx) = outbound_queue::channel(self.config.outbound);
+        let messages = [
+            RouterMessage::Connect {
+                session_id,
+                reply_tx,
+                addr: self.addr,
+            },
+            RouterMessage::TokenLogin {
+                token: token.trim().to_string(),
+                session_id,
+            },
+            request(session_id),
+        ];
+        for msg in messages {
+            if self.router_tx.send(msg).await.is_err() {
+                return error(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    ErrorCode::Internal,
+                    "Shutting down".to_string(),
+                );
+            }
+        }
+
+        // The router keeps the user's own traffic away from API sessions, but
+        // anything that isn't an answer is skipped all the same.
+        let answer = time::timeout(REQUEST_TIMEOUT, async {
+            while let Some(reply) = reply_rx.recv().await {
+                if answers(&reply) {
+                    return Some(reply);
+                }
+            }
+            None
+        })
+        .await;
+        let _ = self
+            .router_tx
+            .send(RouterMessage::Disconnect { session_id })
+            .await;
+
+        match answer {
+            Ok(Some(reply)) => respond(reply),
+            Ok(None) => {
+                debug!("HTTP session {} was dropped by the router", session_id);
+                error(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    ErrorCode::Internal,
+                    "The server dropped the request".to_string(),
+                )
+            }
+            Err(_) => {
+                debug!("HTTP session {} got no answer in time", session_id);
+                error(
+                    StatusCode::GATEWAY_TIMEOUT,
+                    ErrorCode::Internal,
+                    "The server took too long to answer".to_string(),
+                )
+            }
+        }
+    }
+}
+
+/// Whether the reply is the router's answer to an API request.
+fn answers(reply: &ClientReply) -> bool {
+    matches!(
+        reply,
+        ClientReply::Err { .. }
+            | ClientReply::Rooms { .. }
+            | ClientReply::RoomCreated { .. }
+            | ClientReply::History { .. }
+            | ClientReply::MessageAck { .. }
+            | ClientReply::User { .. }
+    )
+}
+
+fn respond(reply: ClientReply) -> Response {
+    match reply {
+        ClientReply::Rooms { rooms } => reply::json(&rooms).into_response(),
+        ClientReply::RoomCreated { room } => {
+            reply::with_status(reply::json(&room), StatusCode::CREATED).into_response()
+        }
+        ClientReply::History {
+            room_id,
+            messages,
+            next,
+        } => reply::json(&HistoryPage {
+            room_id,
+            messages,
+            next,
+        })
+        .into_response(),
+        ClientReply::MessageAck {
+            room_id,
+            message_id,
+            seq,
+            time,
+        } => {
+            let posted = Posted {
+                room_id,
+                message_id,
+                seq,
+                time,
+            };
+            reply::with_status(reply::json(&posted), StatusCode::CREATED).into_response()
+        }
+        ClientReply::User { user } => reply::json(&user).into_response(),
+        ClientReply::Err {
+            code,
+            message,
+            retry_after_ms,
+            ..
+        } => {
+            let body = ErrorBody {
+                code,
+                message,
+                retry_after_ms,
+            };
+            let response = reply::with_status(reply::json(&body), status(code));
+            match retry_after_ms {
+                Some(ms) => {
+                    reply::with_header(response, RETRY_AFTER, ms.div_ceil(1000)).into_response()
+                }
+                None => response.into_response(),
+            }
+        }
+        other => {
+            debug!("Unexpected answer to an HTTP request: {:?}", other);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+fn status(code: ErrorCode) -> StatusCode {
+    match code {
+        ErrorCode::MalformedMessage
+        | ErrorCode::UnsupportedFrame
+        | ErrorCode::InvalidRoom
+        | ErrorCode::InvalidUsername
+        | ErrorCode::InvalidPassword
+        | ErrorCode::InvalidCommand
+        | ErrorCode::InvalidDisplayName
+        | ErrorCode::InvalidReaction
+        | ErrorCode::InvalidDuration => StatusCode::BAD_REQUEST,
+        ErrorCode::NotLoggedIn
+        | ErrorCode::InvalidToken
+        | ErrorCode::BadPassword
+        | ErrorCode::LoginTimeout => StatusCode::UNAUTHORIZED,
+        ErrorCode::Forbidden | ErrorCode::Banned | ErrorCode::Muted => StatusCode::FORBIDDEN,
+        ErrorCode::NotInRoom
+        | ErrorCode::RoomNotFound
+        | ErrorCode::UnknownUser
+        | ErrorCode::MessageNotFound => StatusCode::NOT_FOUND,
+        ErrorCode::AlreadyLoggedIn
+        | ErrorCode::AlreadyInRoom
+        | ErrorCode::SessionClosing
+        | ErrorCode::RoomFull
+        | ErrorCode::RoomNameTaken
+        | ErrorCode::UsernameTaken => StatusCode::CONFLICT,
+        ErrorCode::MessageRejected => StatusCode::UNPROCESSABLE_ENTITY,
+        ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+        ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn error(status: StatusCode, code: ErrorCode, message: String) -> Response {
+    let body = ErrorBody {
+        code,
+        message: Some(message),
+        retry_after_ms: None,
+    };
+    reply::with_status(reply::json(&body), status).into_response()
+}
+
+/// Bodies and query strings that don't parse get the same JSON errors as
+/// everything else, anything warp can't route keeps its own response.
+async fn bad_request(rejection: Rejection) -> Result<Response, Rejection> {
+    if let Some(e) = rejection.find::<BodyDeserializeError>() {
+        return Ok(error(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::MalformedMessage,
+            format!("Malformed body: {}", e),
+        ));
+    }
+    if let Some(e) = rejection.find::<InvalidQuery>() {
+        return Ok(error(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::MalformedMessage,
+            e.to_string(),
+        ));
+    }
+    Err(rejection)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::rate_limit::{Rate, RateLimits};
+    use crate::router::{self, Router};
+    use crate::storage;
+    use crate::user_manager::UserManager;
+
+    const TOKEN: &str = "Bearer s3cret";
+
+    /// The routes in front of a real router, with "ci" behind `TOKEN` and
+    /// room for one history page a minute.
+    fn api() -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+        let config = Arc::new(ServerConfig {
+            database: storage::IN_MEMORY.to_string(),
+            api_tokens: HashMap::from([("s3cret".to_string(), "ci".to_string())]),
+            rate_limits: RateLimits {
+                history: Rate::new(1, 60),
+                ..RateLimits::default()
+            },
+            ..ServerConfig::default()
+        });
+
+        let mut storage = storage::open(&config.database).unwrap();
+        let ci = UserManager::default()
+            .new_user("ci", "CI", "not a hash".to_string())
+            .unwrap();
+        storage.insert_user(&ci).unwrap();
+
+        let (router_tx, router_rx) = mpsc::channel(100);
+        let router = Router::load(storage, Arc::clone(&config), router_tx.downgrade()).unwrap();
+        router::start(router, router_rx);
+        routes(router_tx, config)
+    }
+
+    fn get(path: &str) -> warp::test::RequestBuilder {
+        warp::test::request()
+            .method("GET")
+            .path(path)
+            .header("authorization", TOKEN)
+    }
+
+    fn post(path: &str, body: &str) -> warp::test::RequestBuilder {
+        warp::test::request()
+            .method("POST")
+            .path(path)
+            .header("authorization", TOKEN)
+            .header("content-type", "application/json")
+            .body(body)
+    }
+
+    fn json(body: &[u8]) -> Value {
+        serde_json::from_slice(body).unwrap()
+    }
+
+    async fn create_room(
+        api: &(impl Filter<Extract = (Response,), Error = Rejection> + Clone + 'static),
+    ) -> u128 {
+        let response = post("/api/rooms", r#"{"name":"deploys","capacity":10}"#)
+            .reply(api)
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        // Ids don't fit in a `Value` number.
+        #[derive(Deserialize)]
+        struct Created {
+            id: u128,
+        }
+        serde_json::from_slice::<Created>(response.body())
+            .unwrap()
+            .id
+    }
+
+    #[tokio::test]
+    async fn needs_a_known_token() {
+        let api = api();
+        let response = warp::test::request().path("/api/rooms").reply(&api).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = warp::test::request()
+            .path("/api/rooms")
+            .header("authorization", "Bearer nope")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(json(response.body())["code"], "invalid_token");
+
+        let response = get("/api/rooms").reply(&api).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn missing_rooms_and_users_are_404() {
+        let api = api();
+        let response = get("/api/rooms/12/messages").reply(&api).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(json(response.body())["code"], "room_not_found");
+
+        let response = get("/api/users/zed").reply(&api).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(json(response.body())["code"], "unknown_user");
+    }
+
+    #[tokio::test]
+    async fn rate_limited_requests_are_429_with_retry_after() {
+        let api = api();
+        let room_id = create_room(&api).await;
+        let path = format!("/api/rooms/{}/messages", room_id);
+
+        let response = get(&path).reply(&api).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = get(&path).reply(&api).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "60");
+        assert_eq!(json(response.body())["code"], "rate_limited");
+    }
+
+    #[tokio::test]
+    async fn posts_go_through_commands_like_sent_messages() {
+        let api = api();
+        let room_id = create_room(&api).await;
+        let path = format!("/api/rooms/{}/messages", room_id);
+
+        for text in ["/me deployed", "//etc is fine"] {
+            let response = post(&path, &format!(r#"{{"text":"{}"}}"#, text))
+                .reply(&api)
+                .await;
+            assert_eq!(response.status(), StatusCode::CREATED, "{}", text);
+        }
+        let response = post(&path, r#"{"text":"/leave"}"#).reply(&api).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = get(&path).reply(&api).await;
+        let page = json(response.body());
+        let texts: Vec<&str> = page["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|message| message["text"].as_str().unwrap())
+            .collect();
+        assert_eq!(texts, ["* CI deployed", "/etc is fine"]);
+    }
+}
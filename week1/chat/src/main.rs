@@ -1,40 +1,64 @@
 use std::net::SocketAddr;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::sync::{mpsc, oneshot};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
 
+use config::ServerConfig;
 use log::{info, error};
-use types::{ClientReply, RouterMessage};
+use router::Router;
+use types::RouterMessage;
+mod config;
+mod outbound_queue;
 mod router;
+mod sqlite_storage;
+mod storage;
 mod types;
 mod session_handler;
 mod connection_manager;
 mod room_manager;
 mod user_manager;
+mod direct_manager;
+mod reactions;
+mod commands;
+mod rate_limit;
+mod filters;
+mod bots;
+mod http_api;
 
 
-const ADDRESS: &str = "127.0.0.1:3030";
-
 #[tokio::main]
 async fn main() {
     env_logger::init();
 
-    let addr: SocketAddr = ADDRESS.to_string().parse().expect("Invalid address");
+    let config = Arc::new(ServerConfig::from_env());
+
+    let addr: SocketAddr = config.address.parse().expect("Invalid address");
     
     let listener = TcpListener::bind(&addr).await.expect("Failed to bind");
 
     info!("Server listening on {}", addr);
 
-    let (router_tx, mut router_rx) = mpsc::channel::<RouterMessage>(100);;
+    let (router_tx, router_rx) = mpsc::channel::<RouterMessage>(100);
+
+    let storage = storage::open(&config.database).expect("Failed to open storage");
+    let mut router = Router::load(storage, Arc::clone(&config), router_tx.downgrade()).expect("Failed to load state from storage");
+
+    router.start_bots();
 
-    let (user_tx, mut user_rx) = oneshot::channel::<ClientReply>();
+    router::start(router, router_rx);
 
-    tokio::spawn(router::start(router_rx, user_tx));
+    let http_addr: SocketAddr = config.http_address.parse().expect("Invalid HTTP address");
+    let (http_addr, http_server) = warp::serve(http_api::routes(router_tx.clone(), Arc::clone(&config)))
+        .try_bind_ephemeral(http_addr)
+        .expect("Failed to bind the HTTP API");
+    info!("HTTP API listening on {}", http_addr);
+    tokio::spawn(http_server);
 
     while let Ok((stream, addr)) = listener.accept().await {
         let router_tx_clone = router_tx.clone();
+        let config = Arc::clone(&config);
         tokio::spawn(async move {
-            if let Err(e) = connection_manager::handle_connection(stream, addr, router_tx_clone, user_rx) {
+            if let Err(e) = connection_manager::handle_connection(stream, addr, router_tx_clone, config).await {
                 error!("Error handling a connection from {}: {:?}", addr, e);
             }
         });
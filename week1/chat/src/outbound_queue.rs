@@ -0,0 +1,275 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use tokio::sync::Notify;
+
+use crate::types::ClientReply;
+
+/// What to do with a session that reads slower than the router writes to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SlowConsumerPolicy {
+    /// Drop the oldest queued replies and tell the client how many it missed,
+    /// so it can resync.
+    DropOldest,
+    /// Like `DropOldest`, but hang up once more than `max_missed` replies were
+    /// dropped before the client caught up.
+    Disconnect { max_missed: u64 },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct QueueConfig {
+    pub capacity: usize,
+    pub policy: SlowConsumerPolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            capacity: 64,
+            policy: SlowConsumerPolicy::DropOldest,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct QueueStats {
+    /// Replies waiting to be written right now.
+    pub depth: usize,
+    pub max_depth: usize,
+    pub delivered: u64,
+    pub dropped: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PushResult {
+    Queued,
+    /// Queued, but something older had to go to make room.
+    Dropped {
+        missed: u64,
+    },
+    /// The policy gave up on this session, the queue is now closed.
+    Overflowed,
+    /// The connection is gone.
+    Closed,
+}
+
+struct Inner {
+    replies: VecDeque<ClientReply>,
+    /// Dropped since the client last got a `Resync`.
+    missed: u64,
+    closed: bool,
+    stats: QueueStats,
+}
+
+struct Shared {
+    inner: Mutex<Inner>,
+    notify: Notify,
+    config: QueueConfig,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        // Nothing in here can be left half updated, so a poisoned lock is still usable.
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn close(&self) {
+        self.lock().closed = true;
+        self.notify.notify_one();
+    }
+}
+
+/// The router's end of a session's queue. Pushing never waits.
+pub(crate) struct QueueSender {
+    shared: Arc<Shared>,
+}
+
+/// The connection's end of a session's queue.
+pub(crate) struct QueueReceiver {
+    shared: Arc<Shared>,
+}
+
+pub(crate) fn channel(config: QueueConfig) -> (QueueSender, QueueReceiver) {
+    let shared = Arc::new(Shared {
+        inner: Mutex::new(Inner {
+            replies: VecDeque::with_capacity(config.capacity),
+            missed: 0,
+            closed: false,
+            stats: QueueStats::default(),
+        }),
+        notify: Notify::new(),
+        config,
+    });
+
+    (
+        QueueSender {
+            shared: Arc::clone(&shared),
+        },
+        QueueReceiver { shared },
+    )
+}
+
+impl QueueSender {
+    pub fn push(&self, reply: ClientReply) -> PushResult {
+        let mut inner = self.shared.lock();
+        if inner.closed {
+            return PushResult::Closed;
+        }
+
+        let mut result = PushResult::Queued;
+        if inner.replies.len() >= self.shared.config.capacity.max(1) {
+            inner.replies.pop_front();
+            inner.missed += 1;
+            inner.stats.dropped += 1;
+            result = PushResult::Dropped {
+                missed: inner.missed,
+            };
+
+            if let SlowConsumerPolicy::Disconnect { max_missed } = self.shared.config.policy {
+                if inner.missed > max_missed {
+                    inner.closed = true;
+                    inner.replies.clear();
+                    drop(inner);
+                    self.shared.notify.notify_one();
+                    return PushResult::Overflowed;
+                }
+            }
+        }
+
+        inner.replies.push_back(reply);
+        inner.stats.depth = inner.replies.len();
+        inner.stats.max_depth = inner.stats.max_depth.max(inner.stats.depth);
+        drop(inner);
+
+        self.shared.notify.notify_one();
+        result
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.shared.lock().stats
+    }
+}
+
+impl fmt::Debug for QueueSender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QueueSender")
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+impl Drop for QueueSender {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+impl QueueReceiver {
+    /// Next reply to write, or `None` once the router has let go of the session.
+    /// If anything was dropped since the last call, a `Resync` comes first.
+    /// Cancel safe, nothing is taken off the queue until it is returned.
+    pub async fn recv(&mut self) -> Option<ClientReply> {
+        loop {
+            {
+                let mut inner = self.shared.lock();
+                if inner.missed > 0 {
+                    let missed = std::mem::take(&mut inner.missed);
+                    return Some(ClientReply::Resync { missed });
+                }
+                if let Some(reply) = inner.replies.pop_front() {
+                    inner.stats.depth = inner.replies.len();
+                    inner.stats.delivered += 1;
+                    return Some(reply);
+                }
+                if inner.closed {
+                    return None;
+                }
+            }
+
+            self.shared.notify.notified().await;
+        }
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.shared.lock().stats
+    }
+}
+
+impl Drop for QueueReceiver {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notice(n: u32) -> ClientReply {
+        ClientReply::Notice {
+            text: n.to_string(),
+        }
+    }
+
+    async fn next_text(rx: &mut QueueReceiver) -> String {
+        match rx.recv().await {
+            Some(ClientReply::Notice { text }) => text,
+            other => panic!("expected a notice, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn drops_the_oldest_and_resyncs_first() {
+        let (tx, mut rx) = channel(QueueConfig {
+            capacity: 2,
+            policy: SlowConsumerPolicy::DropOldest,
+        });
+        assert_eq!(tx.push(notice(1)), PushResult::Queued);
+        assert_eq!(tx.push(notice(2)), PushResult::Queued);
+        assert_eq!(tx.push(notice(3)), PushResult::Dropped { missed: 1 });
+        assert_eq!(tx.push(notice(4)), PushResult::Dropped { missed: 2 });
+
+        assert!(matches!(
+            rx.recv().await,
+            Some(ClientReply::Resync { missed: 2 })
+        ));
+        assert_eq!(next_text(&mut rx).await, "3");
+        assert_eq!(next_text(&mut rx).await, "4");
+
+        // The count starts over once the client was told.
+        assert_eq!(tx.push(notice(5)), PushResult::Queued);
+        assert_eq!(next_text(&mut rx).await, "5");
+
+        let stats = rx.stats();
+        assert_eq!((stats.delivered, stats.dropped, stats.max_depth), (3, 2, 2));
+    }
+
+    #[tokio::test]
+    async fn disconnects_after_too_many_missed() {
+        let (tx, mut rx) = channel(QueueConfig {
+            capacity: 1,
+            policy: SlowConsumerPolicy::Disconnect { max_missed: 1 },
+        });
+        assert_eq!(tx.push(notice(1)), PushResult::Queued);
+        assert_eq!(tx.push(notice(2)), PushResult::Dropped { missed: 1 });
+        assert_eq!(tx.push(notice(3)), PushResult::Overflowed);
+        assert_eq!(tx.push(notice(4)), PushResult::Closed);
+
+        // Whatever was queued is gone, the client only hears what it missed.
+        assert!(matches!(
+            rx.recv().await,
+            Some(ClientReply::Resync { missed: 2 })
+        ));
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn ends_once_the_sender_is_gone() {
+        let (tx, mut rx) = channel(QueueConfig::default());
+        tx.push(notice(1));
+        drop(tx);
+        assert_eq!(next_text(&mut rx).await, "1");
+        assert!(rx.recv().await.is_none());
+    }
+}
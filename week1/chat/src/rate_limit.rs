@@ -0,0 +1,266 @@
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::types::RouterMessage;
+
+/// `burst` requests at once, with one more allowed every `per / burst` after
+/// that. Written as `10/5s` in the environment, `s`, `m` or `h`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Rate {
+    pub burst: u32,
+    pub per: Duration,
+}
+
+impl Rate {
+    pub const fn new(burst: u32, per_secs: u64) -> Self {
+        Rate {
+            burst,
+            per: Duration::from_secs(per_secs),
+        }
+    }
+}
+
+impl FromStr for Rate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected something like 10/5s, got {:?}", s);
+        let (burst, per) = s.trim().split_once('/').ok_or_else(invalid)?;
+        let burst: u32 = burst.trim().parse().map_err(|_| invalid())?;
+
+        let per = per.trim();
+        let (number, scale) = match per.char_indices().last() {
+            Some((i, 's')) => (&per[..i], 1),
+            Some((i, 'm')) => (&per[..i], 60),
+            Some((i, 'h')) => (&per[..i], 60 * 60),
+            _ => (per, 1),
+        };
+        let secs = number
+            .parse::<u64>()
+            .ok()
+            .and_then(|secs| secs.checked_mul(scale))
+            .ok_or_else(invalid)?;
+        if burst == 0 || secs == 0 {
+            return Err(invalid());
+        }
+        Ok(Rate {
+            burst,
+            per: Duration::from_secs(secs),
+        })
+    }
+}
+
+/// One bucket per kind of request, for a session and again for its user.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RateLimits {
+    pub messages: Rate,
+    pub joins: Rate,
+    pub history: Rate,
+    pub activity: Rate,
+    /// Logging in, registering and resuming, which each cost an argon2 hash
+    /// or a guess at a secret. Also kept per address, since there's no user yet.
+    pub auth: Rate,
+    /// Requests turned away. A session that runs out of these is disconnected.
+    pub strikes: Rate,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            messages: Rate::new(10, 10),
+            joins: Rate::new(5, 30),
+            history: Rate::new(20, 10),
+            activity: Rate::new(20, 10),
+            auth: Rate::new(5, 60),
+            strikes: Rate::new(20, 60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Action {
+    /// Anything that puts text in front of other people.
+    Message,
+    Join,
+    /// Anything that reads pages out of storage.
+    History,
+    /// Reactions, typing and read markers, which reach the room without any text.
+    Activity,
+    Auth,
+}
+
+impl Action {
+    /// What the request counts against and which session sent it, or `None`
+    /// for requests that aren't limited.
+    pub fn of(msg: &RouterMessage) -> Option<(Action, u128)> {
+        match *msg {
+            RouterMessage::SendMessage { session_id, .. }
+            | RouterMessage::SendDirect { session_id, .. }
+            | RouterMessage::EditMessage { session_id, .. }
+            | RouterMessage::DeleteMessage { session_id, .. }
+            | RouterMessage::PostMessage { session_id, .. } => Some((Action::Message, session_id)),
+            RouterMessage::JoinRoom { session_id, .. }
+            | RouterMessage::CreateRoom { session_id, .. } => Some((Action::Join, session_id)),
+            RouterMessage::FetchHistory { session_id, .. }
+            | RouterMessage::FetchDirectHistory { session_id, .. }
+            | RouterMessage::FetchThread { session_id, .. }
+            | RouterMessage::FetchRevisions { session_id, .. }
+            | RouterMessage::ReadHistory { session_id, .. } => Some((Action::History, session_id)),
+            RouterMessage::React { session_id, .. }
+            | RouterMessage::Unreact { session_id, .. }
+            | RouterMessage::Typing { session_id, .. }
+            | RouterMessage::MarkRead { session_id, .. } => Some((Action::Activity, session_id)),
+            RouterMessage::Login { session_id, .. }
+            | RouterMessage::Register { session_id, .. }
+            | RouterMessage::Resume { session_id, .. } => Some((Action::Auth, session_id)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// Starts out full.
+    pub fn new(rate: Rate, now: Instant) -> Self {
+        TokenBucket {
+            rate,
+            tokens: rate.burst as f64,
+            last: now,
+        }
+    }
+
+    /// Ok if there is a token to take, otherwise how long until there is.
+    pub fn check(&mut self, now: Instant) -> Result<(), Duration> {
+        let per_token = self.refill(now);
+        if self.tokens >= 1.0 {
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) * per_token))
+        }
+    }
+
+    /// Call after a successful `check`.
+    pub fn take(&mut self) {
+        self.tokens = (self.tokens - 1.0).max(0.0);
+    }
+
+    pub fn try_take(&mut self, now: Instant) -> Result<(), Duration> {
+        self.check(now)?;
+        self.take();
+        Ok(())
+    }
+
+    /// Whether it has refilled all the way, so forgetting it changes nothing.
+    pub fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.rate.burst as f64
+    }
+
+    /// Adds whatever came back since the last call, returns seconds per token.
+    fn refill(&mut self, now: Instant) -> f64 {
+        let per_token = self.rate.per.as_secs_f64() / self.rate.burst as f64;
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed / per_token).min(self.rate.burst as f64);
+        self.last = now;
+        per_token
+    }
+}
+
+/// A bucket for each kind of request.
+#[derive(Debug, Clone)]
+pub(crate) struct Limiter {
+    messages: TokenBucket,
+    joins: TokenBucket,
+    history: TokenBucket,
+    activity: TokenBucket,
+    auth: TokenBucket,
+}
+
+impl Limiter {
+    pub fn new(limits: &RateLimits, now: Instant) -> Self {
+        Limiter {
+            messages: TokenBucket::new(limits.messages, now),
+            joins: TokenBucket::new(limits.joins, now),
+            history: TokenBucket::new(limits.history, now),
+            activity: TokenBucket::new(limits.activity, now),
+            auth: TokenBucket::new(limits.auth, now),
+        }
+    }
+
+    pub fn bucket(&mut self, action: Action) -> &mut TokenBucket {
+        match action {
+            Action::Message => &mut self.messages,
+            Action::Join => &mut self.joins,
+            Action::History => &mut self.history,
+            Action::Activity => &mut self.activity,
+            Action::Auth => &mut self.auth,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!("10/5s".parse(), Ok(Rate::new(10, 5)));
+        assert_eq!(" 3 / 2m ".parse(), Ok(Rate::new(3, 120)));
+        assert_eq!("1/1h".parse(), Ok(Rate::new(1, 3600)));
+        assert_eq!("4/30".parse(), Ok(Rate::new(4, 30)));
+    }
+
+    #[test]
+    fn rejects_bad_rates() {
+        for s in ["", "10", "0/5s", "10/0s", "10/5d", "ten/5s", "10/s", "-1/5s"] {
+            assert!(s.parse::<Rate>().is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn bucket_allows_a_burst_then_says_how_long_to_wait() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(Rate::new(2, 10), now);
+        assert_eq!(bucket.try_take(now), Ok(()));
+        assert_eq!(bucket.try_take(now), Ok(()));
+        assert_eq!(bucket.try_take(now), Err(Duration::from_secs(5)));
+
+        let later = now + Duration::from_secs(2);
+        assert_eq!(bucket.try_take(later), Err(Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn bucket_refills_up_to_the_burst() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(Rate::new(2, 10), now);
+        bucket.try_take(now).unwrap();
+        bucket.try_take(now).unwrap();
+        assert!(!bucket.is_full(now));
+
+        let later = now + Duration::from_secs(5);
+        assert_eq!(bucket.try_take(later), Ok(()));
+        assert!(bucket.try_take(later).is_err());
+
+        let much_later = now + Duration::from_secs(60);
+        assert!(bucket.is_full(much_later));
+        bucket.try_take(much_later).unwrap();
+        bucket.try_take(much_later).unwrap();
+        assert!(bucket.try_take(much_later).is_err());
+    }
+
+    #[test]
+    fn check_leaves_the_token() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(Rate::new(1, 10), now);
+        assert_eq!(bucket.check(now), Ok(()));
+        assert_eq!(bucket.check(now), Ok(()));
+        bucket.take();
+        assert!(bucket.check(now).is_err());
+    }
+}
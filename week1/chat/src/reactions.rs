@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+
+use crate::types::ReactionSummary;
+
+/// Used when `CHAT_REACTIONS` isn't set.
+const DEFAULT_REACTIONS: &str =
+    "thumbsup=👍,thumbsdown=👎,heart=❤️,joy=😂,tada=🎉,eyes=👀,rocket=🚀,fire=🔥";
+
+/// The emoji people may react with. Each one can also be picked by its
+/// shortcode, with or without the colons.
+#[derive(Debug, Clone)]
+pub(crate) struct ReactionSet {
+    /// Shortcode to emoji.
+    shortcodes: HashMap<String, String>,
+    /// Every allowed emoji, in the order they were listed.
+    emoji: Vec<String>,
+}
+
+impl Default for ReactionSet {
+    fn default() -> Self {
+        ReactionSet::parse(DEFAULT_REACTIONS)
+    }
+}
+
+impl ReactionSet {
+    /// Reads a comma separated list of `shortcode=emoji` pairs or bare emoji.
+    pub fn parse(spec: &str) -> Self {
+        let mut set = ReactionSet {
+            shortcodes: HashMap::new(),
+            emoji: Vec::new(),
+        };
+
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let emoji = match entry.split_once('=') {
+                Some((shortcode, emoji)) => {
+                    let emoji = emoji.trim().to_string();
+                    set.shortcodes.insert(
+                        shortcode.trim().trim_matches(':').to_string(),
+                        emoji.clone(),
+                    );
+                    emoji
+                }
+                None => entry.to_string(),
+            };
+            if !set.emoji.contains(&emoji) {
+                set.emoji.push(emoji);
+            }
+        }
+        set
+    }
+
+    /// The emoji to store for what the client sent, if it's allowed.
+    pub fn resolve(&self, input: &str) -> Option<String> {
+        let input = input.trim();
+        if self.emoji.iter().any(|emoji| emoji == input) {
+            return Some(input.to_string());
+        }
+        self.shortcodes.get(input.trim_matches(':')).cloned()
+    }
+}
+
+/// Groups `(user_id, emoji)` pairs by emoji, keeping the order each emoji
+/// first showed up in.
+pub(crate) fn summarize(reactions: &[(u128, String)]) -> Vec<ReactionSummary> {
+    let mut summaries: Vec<ReactionSummary> = Vec::new();
+    for (user_id, emoji) in reactions {
+        match summaries.iter_mut().find(|summary| &summary.emoji == emoji) {
+            Some(summary) => {
+                summary.count += 1;
+                summary.users.push(*user_id);
+            }
+            None => summaries.push(ReactionSummary {
+                emoji: emoji.clone(),
+                count: 1,
+                users: vec![*user_id],
+            }),
+        }
+    }
+    summaries
+}
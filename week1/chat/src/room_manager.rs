@@ -0,0 +1,494 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+
+use chrono::Utc;
+use uuid::Uuid;
+
+use crate::storage::StoredRoom;
+use crate::types::{ErrorCode, Role, RoomInfo, RoomSummary, Seq, Time, UserTextMessage};
+
+pub(crate) struct Room {
+    pub id: u128,
+    pub name: String,
+    pub capacity: u32,
+    /// The user that created the room, who moderates it.
+    pub owner: Option<u128>,
+    /// Sessions in the room, each with the user whose seat it takes. A user
+    /// takes one seat however many sessions they have in, bots take none.
+    pub members: HashMap<u128, Option<u128>>,
+    /// Users with a membership, connected or not, and how far each has read.
+    readers: HashMap<u128, Seq>,
+    moderators: HashSet<u128>,
+    /// Banned users and when each ban ends, `None` if it only ends by hand.
+    bans: HashMap<u128, Option<Time>>,
+    /// Muted users, the same way.
+    mutes: HashMap<u128, Option<Time>>,
+    /// Sequence number of the newest message, 0 while the room is empty.
+    last_seq: Seq,
+    latest: Option<Arc<UserTextMessage>>,
+}
+
+impl Room {
+    /// Builds the room's next message. The sequence number is only taken once
+    /// the message is stored and passed to `commit_message`, so a failed write
+    /// doesn't leave a gap.
+    pub fn next_message(&self, text: String, from: u128, username: String) -> UserTextMessage {
+        UserTextMessage::new(
+            Uuid::new_v4().to_u128_le(),
+            self.id,
+            self.last_seq + 1,
+            Utc::now(),
+            text,
+            from,
+            username,
+        )
+    }
+
+    pub fn commit_message(&mut self, message: &Arc<UserTextMessage>) {
+        if message.seq() > self.last_seq {
+            self.last_seq = message.seq();
+            self.latest = Some(Arc::clone(message));
+        }
+    }
+
+    /// Sets the newest message after a restart.
+    pub fn restore_latest(&mut self, message: Option<Arc<UserTextMessage>>) {
+        self.latest = message;
+    }
+
+    pub fn restore_reader(&mut self, user_id: u128, last_read: Seq) {
+        self.readers.insert(user_id, last_read);
+    }
+
+    /// Makes the user a member, with everything sent so far counted as read.
+    /// Returns where they start reading, or `None` if they already were one.
+    pub fn add_reader(&mut self, user_id: u128) -> Option<Seq> {
+        if self.readers.contains_key(&user_id) {
+            return None;
+        }
+        self.readers.insert(user_id, self.last_seq);
+        Some(self.last_seq)
+    }
+
+    pub fn is_reader(&self, user_id: u128) -> bool {
+        self.readers.contains_key(&user_id)
+    }
+
+    pub fn remove_reader(&mut self, user_id: u128) {
+        self.readers.remove(&user_id);
+    }
+
+    /// Moves the user's read marker forward, never past the newest message.
+    /// Returns the new marker, or `None` if it didn't move.
+    pub fn mark_read(&mut self, user_id: u128, up_to: Seq) -> Result<Option<Seq>, RoomError> {
+        let Some(last_read) = self.readers.get_mut(&user_id) else {
+            return Err(RoomError::NotMember { room_id: self.id });
+        };
+
+        let up_to = up_to.min(self.last_seq);
+        if up_to <= *last_read {
+            return Ok(None);
+        }
+        *last_read = up_to;
+        Ok(Some(up_to))
+    }
+
+    pub fn summary(&self, user_id: u128) -> RoomSummary {
+        let last_read = self.readers.get(&user_id).copied().unwrap_or(self.last_seq);
+        RoomSummary {
+            room: self.info(),
+            last_read,
+            unread: self.last_seq.saturating_sub(last_read),
+            latest: self.latest.clone(),
+        }
+    }
+
+    pub fn stored(&self) -> StoredRoom {
+        StoredRoom {
+            id: self.id,
+            name: self.name.clone(),
+            capacity: self.capacity,
+            last_seq: self.last_seq,
+            owner: self.owner,
+        }
+    }
+
+    pub fn role_of(&self, user_id: u128) -> Role {
+        if self.owner == Some(user_id) {
+            Role::Owner
+        } else if self.moderators.contains(&user_id) {
+            Role::Moderator
+        } else {
+            Role::Member
+        }
+    }
+
+    pub fn is_moderator(&self, user_id: u128) -> bool {
+        self.role_of(user_id) >= Role::Moderator
+    }
+
+    /// Renaming the room takes a moderator.
+    pub fn check_moderator(&self, user_id: u128) -> Result<(), RoomError> {
+        if !self.is_moderator(user_id) {
+            return Err(RoomError::NotModerator);
+        }
+        Ok(())
+    }
+
+    /// Deleting the room takes its owner.
+    pub fn check_owner(&self, user_id: u128) -> Result<(), RoomError> {
+        if self.role_of(user_id) != Role::Owner {
+            return Err(RoomError::NotOwner);
+        }
+        Ok(())
+    }
+
+    /// Checks that `by` may kick, ban or mute `user_id`, which takes being a
+    /// moderator and outranking them.
+    pub fn check_moderate(&self, by: u128, user_id: u128) -> Result<(), RoomError> {
+        let role = self.role_of(by);
+        if role < Role::Moderator {
+            return Err(RoomError::NotModerator);
+        }
+        if role <= self.role_of(user_id) {
+            return Err(RoomError::Outranked);
+        }
+        Ok(())
+    }
+
+    /// Only the owner hands out roles, and ownership isn't one of them.
+    pub fn check_set_role(&self, by: u128, user_id: u128, role: Role) -> Result<(), RoomError> {
+        if self.role_of(by) != Role::Owner {
+            return Err(RoomError::NotOwner);
+        }
+        if role == Role::Owner || self.role_of(user_id) == Role::Owner {
+            return Err(RoomError::OwnerRole);
+        }
+        Ok(())
+    }
+
+    pub fn set_moderator(&mut self, user_id: u128, moderator: bool) {
+        if moderator {
+            self.moderators.insert(user_id);
+        } else {
+            self.moderators.remove(&user_id);
+        }
+    }
+
+    pub fn ban(&mut self, user_id: u128, until: Option<Time>) {
+        self.bans.insert(user_id, until);
+    }
+
+    /// Returns false if the user wasn't banned, or the ban had run out.
+    pub fn unban(&mut self, user_id: u128) -> bool {
+        self.bans.remove(&user_id).is_some_and(still_on)
+    }
+
+    pub fn check_banned(&self, user_id: u128) -> Result<(), RoomError> {
+        match self.bans.get(&user_id) {
+            Some(&until) if still_on(until) => Err(RoomError::Banned { until }),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn mute(&mut self, user_id: u128, until: Option<Time>) {
+        self.mutes.insert(user_id, until);
+    }
+
+    /// Returns false if the user wasn't muted, or the mute had run out.
+    pub fn unmute(&mut self, user_id: u128) -> bool {
+        self.mutes.remove(&user_id).is_some_and(still_on)
+    }
+
+    pub fn check_muted(&self, user_id: u128) -> Result<(), RoomError> {
+        match self.mutes.get(&user_id) {
+            Some(&until) if still_on(until) => Err(RoomError::Muted { until }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Keeps the preview in step when the newest message is edited or deleted.
+    pub fn replace_message(&mut self, message: &Arc<UserTextMessage>) {
+        if self
+            .latest
+            .as_ref()
+            .is_some_and(|latest| latest.id() == message.id())
+        {
+            self.latest = Some(Arc::clone(message));
+        }
+    }
+
+    pub fn info(&self) -> RoomInfo {
+        RoomInfo {
+            id: self.id,
+            name: self.name.clone(),
+            capacity: self.capacity,
+            occupancy: self.occupancy() as u32,
+        }
+    }
+
+    /// Seats taken, counted against `capacity`.
+    pub fn occupancy(&self) -> usize {
+        self.members
+            .values()
+            .flatten()
+            .collect::<HashSet<_>>()
+            .len()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum RoomError {
+    NotFound { room_id: u128 },
+    NoSuchName { name: String },
+    Full { room_id: u128, capacity: u32 },
+    NameTaken { name: String },
+    InvalidName,
+    InvalidCapacity,
+    NotMember { room_id: u128 },
+    MessageNotFound { message_id: u128 },
+    NotAuthor,
+    NotModerator,
+    InvalidReaction { emoji: String },
+    InvalidDuration,
+    Outranked,
+    NotOwner,
+    OwnerRole,
+    Banned { until: Option<Time> },
+    Muted { until: Option<Time> },
+}
+
+impl RoomError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            RoomError::NotFound { .. } | RoomError::NoSuchName { .. } => ErrorCode::RoomNotFound,
+            RoomError::Full { .. } => ErrorCode::RoomFull,
+            RoomError::NameTaken { .. } => ErrorCode::RoomNameTaken,
+            RoomError::InvalidName | RoomError::InvalidCapacity => ErrorCode::InvalidRoom,
+            RoomError::NotMember { .. } => ErrorCode::NotInRoom,
+            RoomError::MessageNotFound { .. } => ErrorCode::MessageNotFound,
+            RoomError::NotAuthor
+            | RoomError::NotModerator
+            | RoomError::Outranked
+            | RoomError::NotOwner
+            | RoomError::OwnerRole => ErrorCode::Forbidden,
+            RoomError::Banned { .. } => ErrorCode::Banned,
+            RoomError::Muted { .. } => ErrorCode::Muted,
+            RoomError::InvalidReaction { .. } => ErrorCode::InvalidReaction,
+            RoomError::InvalidDuration => ErrorCode::InvalidDuration,
+        }
+    }
+}
+
+impl fmt::Display for RoomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoomError::NotFound { room_id } => write!(f, "Room {} does not exist", room_id),
+            RoomError::NoSuchName { name } => write!(f, "There is no room called {:?}", name),
+            RoomError::Full { room_id, capacity } => {
+                write!(f, "Room {} is full ({} members)", room_id, capacity)
+            }
+            RoomError::NameTaken { name } => write!(f, "A room called {:?} already exists", name),
+            RoomError::InvalidName => write!(f, "Room name can't be empty"),
+            RoomError::InvalidCapacity => write!(f, "Room capacity must be at least 1"),
+            RoomError::NotMember { room_id } => write!(f, "Not a member of room {}", room_id),
+            RoomError::MessageNotFound { message_id } => {
+                write!(f, "Message {} does not exist", message_id)
+            }
+            RoomError::NotAuthor => write!(f, "Only the author can do that"),
+            RoomError::NotModerator => write!(f, "Only a room moderator can do that"),
+            RoomError::InvalidReaction { emoji } => write!(f, "Can't react with {:?}", emoji),
+            RoomError::InvalidDuration => write!(f, "A ban or mute has to last at least a second"),
+            RoomError::Outranked => write!(f, "Only someone with a higher role can do that"),
+            RoomError::NotOwner => write!(f, "Only the room owner can do that"),
+            RoomError::OwnerRole => write!(f, "The owner role can't be given or taken"),
+            RoomError::Banned { until: Some(until) } => {
+                write!(
+                    f,
+                    "You are banned from this room until {}",
+                    until.format("%Y-%m-%d %H:%M UTC")
+                )
+            }
+            RoomError::Banned { until: None } => write!(f, "You are banned from this room"),
+            RoomError::Muted { until: Some(until) } => {
+                write!(
+                    f,
+                    "You are muted in this room until {}",
+                    until.format("%Y-%m-%d %H:%M UTC")
+                )
+            }
+            RoomError::Muted { until: None } => write!(f, "You are muted in this room"),
+        }
+    }
+}
+
+impl std::error::Error for RoomError {}
+
+/// Whether a ban or mute ending at `until` is still in force.
+fn still_on(until: Option<Time>) -> bool {
+    until.is_none_or(|until| until > Utc::now())
+}
+
+/// All rooms on the server. Owned by the router, membership is tracked by
+/// session id so one user can sit in a room from several connections.
+#[derive(Default)]
+pub(crate) struct RoomManager {
+    rooms: HashMap<u128, Room>,
+}
+
+impl RoomManager {
+    /// Builds a new, empty room. Nothing is stored until `insert`.
+    pub fn new_room(&self, name: &str, capacity: u32, owner: u128) -> Result<Room, RoomError> {
+        let name = self.check_name(name, None)?;
+        if capacity == 0 {
+            return Err(RoomError::InvalidCapacity);
+        }
+
+        Ok(Room {
+            id: Uuid::new_v4().to_u128_le(),
+            name,
+            capacity,
+            owner: Some(owner),
+            members: HashMap::new(),
+            readers: HashMap::new(),
+            moderators: HashSet::new(),
+            bans: HashMap::new(),
+            mutes: HashMap::new(),
+            last_seq: 0,
+            latest: None,
+        })
+    }
+
+    pub fn insert(&mut self, room: Room) -> &Room {
+        self.rooms.entry(room.id).or_insert(room)
+    }
+
+    /// Brings back a room from storage, with nobody in it.
+    pub fn restore(&mut self, room: StoredRoom) -> &mut Room {
+        self.rooms.entry(room.id).or_insert(Room {
+            id: room.id,
+            name: room.name,
+            capacity: room.capacity,
+            owner: room.owner,
+            members: HashMap::new(),
+            readers: HashMap::new(),
+            moderators: HashSet::new(),
+            bans: HashMap::new(),
+            mutes: HashMap::new(),
+            last_seq: room.last_seq,
+            latest: None,
+        })
+    }
+
+    pub fn list(&self) -> Vec<RoomInfo> {
+        let mut rooms: Vec<RoomInfo> = self.rooms.values().map(Room::info).collect();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        rooms
+    }
+
+    /// Every room the user is a member of, by name.
+    pub fn summaries(&self, user_id: u128) -> Vec<RoomSummary> {
+        let mut rooms: Vec<RoomSummary> = self
+            .rooms
+            .values()
+            .filter(|room| room.readers.contains_key(&user_id))
+            .map(|room| room.summary(user_id))
+            .collect();
+        rooms.sort_by(|a, b| a.room.name.cmp(&b.room.name));
+        rooms
+    }
+
+    /// Validates a new name for the room and returns it trimmed.
+    pub fn check_rename(&self, room_id: u128, name: &str) -> Result<String, RoomError> {
+        if !self.rooms.contains_key(&room_id) {
+            return Err(RoomError::NotFound { room_id });
+        }
+        self.check_name(name, Some(room_id))
+    }
+
+    pub fn rename(&mut self, room_id: u128, name: &str) -> Result<&Room, RoomError> {
+        let name = self.check_rename(room_id, name)?;
+        let room = self.get_mut(room_id)?;
+        room.name = name;
+        Ok(room)
+    }
+
+    /// Removes the room and hands it back so the caller can kick out its members.
+    pub fn delete(&mut self, room_id: u128) -> Result<Room, RoomError> {
+        self.rooms
+            .remove(&room_id)
+            .ok_or(RoomError::NotFound { room_id })
+    }
+
+    /// Puts the session in the room. `seat` is the user it sits in for, which
+    /// only needs a free seat if none of their other sessions already has one.
+    pub fn join(
+        &mut self,
+        room_id: u128,
+        session_id: u128,
+        seat: Option<u128>,
+    ) -> Result<&Room, RoomError> {
+        let room = self.get_mut(room_id)?;
+        if let Some(user_id) = seat {
+            let seated = room.members.values().any(|&taken| taken == Some(user_id));
+            if !seated && room.occupancy() >= room.capacity as usize {
+                return Err(RoomError::Full {
+                    room_id,
+                    capacity: room.capacity,
+                });
+            }
+        }
+
+        room.members.insert(session_id, seat);
+        Ok(room)
+    }
+
+    pub fn leave(&mut self, room_id: u128, session_id: u128) {
+        if let Some(room) = self.rooms.get_mut(&room_id) {
+            room.members.remove(&session_id);
+        }
+    }
+
+    /// The room with exactly this name, trimmed.
+    pub fn find_by_name(&self, name: &str) -> Result<&Room, RoomError> {
+        let name = name.trim();
+        self.rooms
+            .values()
+            .find(|room| room.name == name)
+            .ok_or_else(|| RoomError::NoSuchName {
+                name: name.to_string(),
+            })
+    }
+
+    pub fn get(&self, room_id: u128) -> Result<&Room, RoomError> {
+        self.rooms
+            .get(&room_id)
+            .ok_or(RoomError::NotFound { room_id })
+    }
+
+    pub fn get_mut(&mut self, room_id: u128) -> Result<&mut Room, RoomError> {
+        self.rooms
+            .get_mut(&room_id)
+            .ok_or(RoomError::NotFound { room_id })
+    }
+
+    /// Trims the name and makes sure no other room uses it. `renaming` is the
+    /// room being renamed, which may keep its own name.
+    fn check_name(&self, name: &str, renaming: Option<u128>) -> Result<String, RoomError> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(RoomError::InvalidName);
+        }
+        if self
+            .rooms
+            .values()
+            .any(|room| room.name == name && Some(room.id) != renaming)
+        {
+            return Err(RoomError::NameTaken {
+                name: name.to_string(),
+            });
+        }
+        Ok(name.to_string())
+    }
+}
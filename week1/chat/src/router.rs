@@ -1,11 +1,2588 @@
-use tokio::sync::mpsc::Receiver;
-use tokio::sync::oneshot::Sender;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
 
-use crate::types::{self, ClientReply, RouterMessage};
+use chrono::{TimeDelta, Utc};
+use log::{debug, error, info, warn};
+use tokio::runtime::Handle;
+use tokio::sync::mpsc::{Receiver, WeakSender};
+use uuid::Uuid;
 
+use crate::bots::{self, BotKind};
 
-pub(crate) async fn start ( router_rx: Receiver<RouterMessage>, user_tx: Sender<ClientReply> ) {
-    todo!();
+use crate::commands::{self, Command, Input};
+use crate::config::ServerConfig;
+use crate::direct_manager::DirectManager;
+use crate::outbound_queue::{self, PushResult, QueueSender};
+use crate::rate_limit::{Action, Limiter, TokenBucket};
+use crate::reactions;
+use crate::room_manager::{RoomError, RoomManager};
+use crate::session_handler::{ResumeTokens, SessionError, SessionRegistry, SessionState};
+use crate::storage::{self, Storage, StoredBan, StoredMute};
+use crate::types::{
+    ClientReply, Cursor, Error, ErrorCode, MemberInfo, Pair, Presence, Role, RoomEvent,
+    RouterMessage, Seq, ThreadSummary, Time, UserInfo, UserTextMessage,
+};
+use crate::user_manager::{self, UserError, UserManager};
 
+/// Owns every session, room, user and message. Only ever touched from the
+/// router thread, so none of this needs a lock.
+pub(crate) struct Router {
+    sessions: SessionRegistry,
+    resume_tokens: ResumeTokens,
+    rooms: RoomManager,
+    users: UserManager,
+    direct: DirectManager,
+    /// When each user was last connected, kept for users that aren't.
+    last_seen: HashMap<u128, Time>,
+    /// Rate limits shared by all of a user's sessions, on top of each session's own.
+    user_limits: HashMap<u128, Limiter>,
+    /// Auth attempts from each address, shared by every connection from it.
+    addr_limits: HashMap<IpAddr, TokenBucket>,
+    /// Source of truth for anything that outlives a restart. Written before
+    /// the in-memory state above changes.
+    storage: Box<dyn Storage>,
+    config: Arc<ServerConfig>,
+    /// Lets work that was pushed off the router thread report back in.
+    router_tx: WeakSender<RouterMessage>,
 }
 
+/// Runs the router on a thread of its own. Storage calls block, and this
+/// keeps them off the runtime workers that drive the connections. Call from
+/// inside the runtime, the router still spawns blocking work onto it.
+pub(crate) fn start(mut router: Router, mut router_rx: Receiver<RouterMessage>) -> JoinHandle<()> {
+    let runtime = Handle::current();
+    thread::Builder::new()
+        .name("router".to_string())
+        .spawn(move || {
+            let _runtime = runtime.enter();
+            while let Some(msg) = router_rx.blocking_recv() {
+                router.handle(msg);
+            }
+
+            info!("Router channel closed, shutting down");
+        })
+        .expect("Failed to start the router thread")
+}
+
+impl Router {
+    /// Builds the router from whatever `storage` already holds.
+    pub fn load(
+        storage: Box<dyn Storage>,
+        config: Arc<ServerConfig>,
+        router_tx: WeakSender<RouterMessage>,
+    ) -> Result<Self, Error> {
+        let mut users = UserManager::default();
+        for user in storage.users()? {
+            users.insert(user);
+        }
+
+        let mut rooms = RoomManager::default();
+        for room in storage.rooms()? {
+            let latest = storage.messages(room.id, None, 1)?.pop();
+            rooms.restore(room).restore_latest(latest);
+        }
+        for room_id in config.filters.room_ids() {
+            if rooms.get(room_id).is_err() {
+                warn!(
+                    "Message filters are set for room {}, which doesn't exist",
+                    room_id
+                );
+            }
+        }
+        for (room_id, user_id, last_read) in storage.memberships()? {
+            if let Ok(room) = rooms.get_mut(room_id) {
+                room.restore_reader(user_id, last_read);
+            }
+        }
+        for (room_id, user_id) in storage.moderators()? {
+            if let Ok(room) = rooms.get_mut(room_id) {
+                room.set_moderator(user_id, true);
+            }
+        }
+        for ban in storage.bans()? {
+            if let Ok(room) = rooms.get_mut(ban.room_id) {
+                room.ban(ban.user_id, ban.until);
+            }
+        }
+        for mute in storage.mutes()? {
+            if let Ok(room) = rooms.get_mut(mute.room_id) {
+                room.mute(mute.user_id, mute.until);
+            }
+        }
+
+        let last_seen = storage.last_seen()?.into_iter().collect();
+
+        let mut direct = DirectManager::default();
+        for (pair, last_seq) in storage.conversations()? {
+            direct.restore(pair, last_seq);
+        }
+
+        Ok(Router {
+            sessions: SessionRegistry::default(),
+            resume_tokens: ResumeTokens::default(),
+            rooms,
+            users,
+            direct,
+            last_seen,
+            user_limits: HashMap::new(),
+            addr_limits: HashMap::new(),
+            storage,
+            config,
+            router_tx,
+        })
+    }
+
+    /// Puts each configured bot in its rooms, creating its account the first
+    /// time. Call before the router thread starts.
+    pub fn start_bots(&mut self) {
+        let specs = self.config.bots.clone();
+        for spec in specs {
+            let Some(user_id) = self.bot_user(&spec.kind) else {
+                continue;
+            };
+            for name in &spec.rooms {
+                let room_id = match self.rooms.find_by_name(name) {
+                    Ok(room) => room.id,
+                    Err(e) => {
+                        warn!("Not starting {} in {}: {}", spec.kind.username(), name, e);
+                        continue;
+                    }
+                };
+
+                let session_id = Uuid::new_v4().to_u128_le();
+                let (reply_tx, reply_rx) = outbound_queue::channel(self.config.outbound);
+                self.connect(session_id, reply_tx, None);
+                if let Err(e) = self.sessions.login(session_id, user_id) {
+                    warn!("Bot session {} couldn't log in: {}", session_id, e);
+                    continue;
+                }
+                if !self.enter_room(room_id, session_id) {
+                    continue;
+                }
+
+                info!("Started {} in {}", spec.kind.username(), name);
+                tokio::spawn(bots::run(
+                    spec.kind.build(),
+                    user_id,
+                    room_id,
+                    session_id,
+                    reply_rx,
+                    self.router_tx.clone(),
+                ));
+            }
+        }
+    }
+
+    /// The bot's account, made on first use. `None` if a person already has
+    /// the name.
+    fn bot_user(&mut self, kind: &BotKind) -> Option<u128> {
+        if let Ok(user) = self.users.find_by_username(kind.username()) {
+            if user.bot {
+                return Some(user.id);
+            }
+            warn!(
+                "Not starting the {} bot, a person already has that username",
+                kind.username()
+            );
+            return None;
+        }
+
+        let user = match self.users.new_bot(kind.username(), kind.display_name()) {
+            Ok(user) => user,
+            Err(e) => {
+                warn!("Not starting the {} bot: {}", kind.username(), e);
+                return None;
+            }
+        };
+        if let Err(e) = self.storage.insert_user(&user) {
+            error!("Failed to store bot user {}: {}", kind.username(), e);
+            return None;
+        }
+        let user_id = user.id;
+        self.users.insert(user);
+        Some(user_id)
+    }
+
+    fn handle(&mut self, msg: RouterMessage) {
+        if let Some((action, session_id)) = Action::of(&msg) {
+            if !self.check_rate(action, session_id) {
+                return;
+            }
+        }
+
+        match msg {
+            RouterMessage::Connect {
+                session_id,
+                reply_tx,
+                addr,
+            } => self.connect(session_id, reply_tx, addr),
+            RouterMessage::Disconnect { session_id } => self.disconnect(session_id),
+            RouterMessage::Register {
+                username,
+                display_name,
+                password,
+                session_id,
+            } => self.register(username, display_name, password, session_id),
+            RouterMessage::PasswordHashed {
+                username,
+                display_name,
+                password_hash,
+                session_id,
+            } => self.password_hashed(&username, &display_name, password_hash, session_id),
+            RouterMessage::Login {
+                username,
+                password,
+                session_id,
+            } => self.login(&username, password, session_id),
+            RouterMessage::PasswordChecked {
+                user_id,
+                valid,
+                session_id,
+            } => self.password_checked(user_id, valid, session_id),
+            RouterMessage::Resume {
+                token,
+                last_seen,
+                session_id,
+            } => self.resume(&token, &last_seen, session_id),
+            RouterMessage::JoinRoom {
+                room_id,
+                session_id,
+            } => self.join_room(room_id, session_id),
+            RouterMessage::SendMessage {
+                room_id,
+                message,
+                parent_id,
+                session_id,
+            } => self.send_message(room_id, message, parent_id, session_id),
+            RouterMessage::LeaveRoom { session_id } => self.leave_room(session_id),
+            RouterMessage::FetchHistory {
+                room_id,
+                cursor,
+                limit,
+                session_id,
+            } => self.fetch_history(room_id, cursor, limit, session_id),
+            RouterMessage::CreateRoom {
+                name,
+                capacity,
+                session_id,
+            } => self.create_room(&name, capacity, session_id),
+            RouterMessage::ListRooms { session_id } => self.list_rooms(session_id),
+            RouterMessage::RenameRoom {
+                room_id,
+                name,
+                session_id,
+            } => self.rename_room(room_id, &name, session_id),
+            RouterMessage::DeleteRoom {
+                room_id,
+                session_id,
+            } => self.delete_room(room_id, session_id),
+            RouterMessage::SendDirect {
+                to_user_id,
+                text,
+                session_id,
+            } => self.send_direct(to_user_id, text, session_id),
+            RouterMessage::FetchDirectHistory {
+                user_id,
+                cursor,
+                limit,
+                session_id,
+            } => self.fetch_direct_history(user_id, cursor, limit, session_id),
+            RouterMessage::SetAway { away, session_id } => self.set_away(away, session_id),
+            RouterMessage::Typing {
+                room_id,
+                session_id,
+            } => self.typing(room_id, session_id),
+            RouterMessage::MarkRead {
+                room_id,
+                up_to,
+                session_id,
+            } => self.mark_read(room_id, up_to, session_id),
+            RouterMessage::MyRooms { session_id } => self.my_rooms(session_id),
+            RouterMessage::EditMessage {
+                room_id,
+                message_id,
+                text,
+                session_id,
+            } => self.edit_message(room_id, message_id, text, session_id),
+            RouterMessage::DeleteMessage {
+                room_id,
+                message_id,
+                session_id,
+            } => self.delete_message(room_id, message_id, session_id),
+            RouterMessage::FetchRevisions {
+                room_id,
+                message_id,
+                session_id,
+            } => self.fetch_revisions(room_id, message_id, session_id),
+            RouterMessage::React {
+                message_id,
+                emoji,
+                session_id,
+            } => self.react(message_id, &emoji, true, session_id),
+            RouterMessage::Unreact {
+                message_id,
+                emoji,
+                session_id,
+            } => self.react(message_id, &emoji, false, session_id),
+            RouterMessage::FetchThread {
+                room_id,
+                root_id,
+                cursor,
+                limit,
+                session_id,
+            } => self.fetch_thread(room_id, root_id, cursor, limit, session_id),
+            RouterMessage::SetRole {
+                room_id,
+                user_id,
+                role,
+                session_id,
+            } => self.set_role(room_id, user_id, role, session_id),
+            RouterMessage::Kick {
+                room_id,
+                user_id,
+                reason,
+                session_id,
+            } => self.kick(room_id, user_id, reason, session_id),
+            RouterMessage::Ban {
+                room_id,
+                user_id,
+                duration_secs,
+                reason,
+                session_id,
+            } => self.ban(room_id, user_id, duration_secs, reason, session_id),
+            RouterMessage::Unban {
+                room_id,
+                user_id,
+                session_id,
+            } => self.unban(room_id, user_id, session_id),
+            RouterMessage::Mute {
+                room_id,
+                user_id,
+                duration_secs,
+                session_id,
+            } => self.mute(room_id, user_id, duration_secs, session_id),
+            RouterMessage::Unmute {
+                room_id,
+                user_id,
+                session_id,
+            } => self.unmute(room_id, user_id, session_id),
+            RouterMessage::TokenLogin { token, session_id } => self.token_login(&token, session_id),
+            RouterMessage::PostMessage {
+                room_id,
+                message,
+                session_id,
+            } => self.post_message(room_id, message, session_id),
+            RouterMessage::ReadHistory {
+                room_id,
+                cursor,
+                limit,
+                session_id,
+            } => self.read_history(room_id, cursor, limit, session_id),
+            RouterMessage::FindUser {
+                username,
+                session_id,
+            } => self.find_user(&username, session_id),
+        }
+    }
+
+    fn connect(&mut self, session_id: u128, reply_tx: QueueSender, addr: Option<IpAddr>) {
+        if !self
+            .sessions
+            .register(session_id, reply_tx, addr, &self.config.rate_limits)
+        {
+            warn!("Session {} is already registered", session_id);
+            return;
+        }
+        debug!("Session {} connected", session_id);
+    }
+
+    fn disconnect(&mut self, session_id: u128) {
+        // API requests come and go without anyone seeing them.
+        let user_id = self
+            .sessions
+            .get(session_id)
+            .filter(|session| !session.api)
+            .and_then(|session| session.state.user_id());
+        let before = user_id.map(|user_id| self.sessions.presence_of(user_id));
+
+        let Some(session) = self.sessions.get_mut(session_id) else {
+            return;
+        };
+        let room_id = session.state.room_id();
+        session.state.close();
+
+        if let Some(token) = &session.resume_token {
+            self.resume_tokens
+                .detach(token, room_id, self.config.resume_ttl);
+        }
+        if let Some(room_id) = room_id {
+            self.remove_member(room_id, session_id, user_id);
+        }
+        self.sessions.unregister(session_id);
+        debug!("Session {} disconnected", session_id);
+
+        // Only addresses that tried to log in lately are worth remembering.
+        let now = Instant::now();
+        self.addr_limits.retain(|_, bucket| !bucket.is_full(now));
+
+        if let (Some(user_id), Some(before)) = (user_id, before) {
+            if self.sessions.presence_of(user_id) == Presence::Offline {
+                self.record_last_seen(user_id);
+            }
+            self.presence_changed(user_id, before, room_id);
+        }
+    }
+
+    /// Takes a token for the request from the session's and the user's
+    /// buckets, or turns it away with how long to wait. Sessions that keep
+    /// going regardless are disconnected. Auth comes before there is a user,
+    /// so it's the address's bucket instead.
+    ///
+    /// An API session only lasts one request, so only the user's buckets
+    /// apply to it, and it has no strikes to run out of.
+    fn check_rate(&mut self, action: Action, session_id: u128) -> bool {
+        let now = Instant::now();
+        let limits = &self.config.rate_limits;
+        let Some(session) = self.sessions.get_mut(session_id) else {
+            return true;
+        };
+        // Bots are trusted to pace themselves.
+        if session
+            .state
+            .user_id()
+            .and_then(|user_id| self.users.get(user_id))
+            .is_some_and(|user| user.bot)
+        {
+            return true;
+        }
+        let mut shared = if action == Action::Auth {
+            session.addr.map(|addr| {
+                self.addr_limits
+                    .entry(addr)
+                    .or_insert_with(|| TokenBucket::new(limits.auth, now))
+            })
+        } else {
+            session.state.user_id().map(|user_id| {
+                self.user_limits
+                    .entry(user_id)
+                    .or_insert_with(|| Limiter::new(limits, now))
+                    .bucket(action)
+            })
+        };
+
+        let api = session.api;
+        let session_wait = if api {
+            None
+        } else {
+            session.limiter.bucket(action).check(now).err()
+        };
+        let shared_wait = shared.as_mut().and_then(|bucket| bucket.check(now).err());
+        let Some(retry_after) = session_wait.max(shared_wait) else {
+            session.limiter.bucket(action).take();
+            if let Some(bucket) = shared {
+                bucket.take();
+            }
+            return true;
+        };
+
+        let user_id = session.state.user_id();
+        if !self.strike(session_id) {
+            return false;
+        }
+        debug!("Session {} is over its {:?} rate limit", session_id, action);
+        self.reply(
+            session_id,
+            ClientReply::Err {
+                code: ErrorCode::RateLimited,
+                message: Some(format!(
+                    "Too many requests, try again in {:.1}s",
+                    retry_after.as_secs_f64()
+                )),
+                user_id,
+                retry_after_ms: Some(retry_after.as_millis() as u64),
+            },
+        );
+        false
+    }
+
+    /// Counts a turned-away request against the session, and disconnects it
+    /// once it runs out of strikes. Returns whether it's still connected.
+    fn strike(&mut self, session_id: u128) -> bool {
+        let Some(session) = self.sessions.get_mut(session_id) else {
+            return false;
+        };
+        if session.api || session.strikes.try_take(Instant::now()).is_ok() {
+            return true;
+        }
+
+        warn!(
+            "Session {} kept getting turned away, disconnecting",
+            session_id
+        );
+        self.reply_err(
+            session_id,
+            ErrorCode::RateLimited,
+            "Too many requests, disconnecting".to_string(),
+        );
+        self.disconnect(session_id);
+        false
+    }
+
+    fn register(
+        &mut self,
+        username: String,
+        display_name: String,
+        password: String,
+        session_id: u128,
+    ) {
+        if self.sessions.get(session_id).is_none() {
+            warn!("Register from unknown session {}", session_id);
+            return;
+        }
+
+        if let Err(e) = self
+            .users
+            .check_registration(&username, &display_name, &password)
+        {
+            self.reply_user_err(session_id, e);
+            return;
+        }
+
+        let router_tx = self.router_tx.clone();
+        tokio::task::spawn_blocking(move || {
+            let password_hash = user_manager::hash_password(&password).ok();
+            if let Some(router_tx) = router_tx.upgrade() {
+                let _ = router_tx.blocking_send(RouterMessage::PasswordHashed {
+                    username,
+                    display_name,
+                    password_hash,
+                    session_id,
+                });
+            }
+        });
+    }
+
+    fn password_hashed(
+        &mut self,
+        username: &str,
+        display_name: &str,
+        password_hash: Option<String>,
+        session_id: u128,
+    ) {
+        let Some(password_hash) = password_hash else {
+            self.reply_user_err(session_id, UserError::Internal);
+            return;
+        };
+
+        let user = match self.users.new_user(username, display_name, password_hash) {
+            Ok(user) => user,
+            Err(e) => {
+                self.reply_user_err(session_id, e);
+                return;
+            }
+        };
+
+        if let Err(e) = self.storage.insert_user(&user) {
+            self.reply_internal(session_id, e);
+            return;
+        }
+
+        let user = self.users.insert(user);
+        let user_id = user.id;
+        let username = user.username.clone();
+        info!("Registered user {} as {}", username, user_id);
+        self.reply(session_id, ClientReply::Registered { user_id, username });
+    }
+
+    fn login(&mut self, username: &str, password: String, session_id: u128) {
+        let Some(session) = self.sessions.get(session_id) else {
+            warn!("Login from unknown session {}", session_id);
+            return;
+        };
+
+        // Don't pay for argon2 if the session couldn't log in anyway.
+        if let Err(e) = session.state.can_login() {
+            self.reply_session_err(session_id, e);
+            return;
+        }
+
+        let (user_id, password_hash) = match self.users.find_by_username(username) {
+            Ok(user) => (user.id, user.password_hash().to_string()),
+            Err(e) => {
+                self.reply_user_err(session_id, e);
+                self.strike(session_id);
+                return;
+            }
+        };
+
+        let router_tx = self.router_tx.clone();
+        tokio::task::spawn_blocking(move || {
+            let valid = user_manager::verify_password(&password_hash, &password);
+            if let Some(router_tx) = router_tx.upgrade() {
+                let _ = router_tx.blocking_send(RouterMessage::PasswordChecked {
+                    user_id,
+                    valid,
+                    session_id,
+                });
+            }
+        });
+    }
+
+    fn password_checked(&mut self, user_id: u128, valid: bool, session_id: u128) {
+        if !valid {
+            self.reply_user_err(session_id, UserError::BadPassword);
+            self.strike(session_id);
+            return;
+        }
+
+        // The session may have gone away or logged in while we were hashing.
+        let before = self.sessions.presence_of(user_id);
+        let session = match self.sessions.login(session_id, user_id) {
+            Ok(session) => session,
+            Err(e) => {
+                self.reply_session_err(session_id, e);
+                return;
+            }
+        };
+        let resume_token = self.resume_tokens.issue(user_id, session_id);
+        session.resume_token = Some(resume_token.clone());
+
+        let Some(user) = self.users.get(user_id) else {
+            return;
+        };
+        let reply = ClientReply::LoggedIn {
+            user_id,
+            username: user.username.clone(),
+            display_name: user.display_name.clone(),
+            resume_token,
+        };
+
+        info!("User {} logged in on session {}", user_id, session_id);
+        self.reply(session_id, reply);
+        self.deliver_pending(user_id, session_id);
+        self.presence_changed(user_id, before, None);
+    }
+
+    fn resume(&mut self, token: &str, last_seen: &HashMap<u128, Seq>, session_id: u128) {
+        let Some(session) = self.sessions.get(session_id) else {
+            warn!("Resume from unknown session {}", session_id);
+            return;
+        };
+        if let Err(e) = session.state.can_login() {
+            self.reply_session_err(session_id, e);
+            return;
+        }
+
+        let Some(resumed) = self.resume_tokens.redeem(token) else {
+            self.reply_session_err(session_id, SessionError::InvalidToken);
+            self.strike(session_id);
+            return;
+        };
+
+        // The old connection is half open and just hasn't noticed yet, take over from it.
+        let mut room_id = resumed.room_id;
+        if let Some(old_session) = resumed.live_session {
+            room_id = self
+                .sessions
+                .get(old_session)
+                .and_then(|session| session.state.room_id());
+            self.disconnect(old_session);
+        }
+
+        let user_id = resumed.user_id;
+        let Some(user) = self.users.get(user_id) else {
+            self.reply_session_err(session_id, SessionError::InvalidToken);
+            return;
+        };
+        let (username, display_name) = (user.username.clone(), user.display_name.clone());
+
+        let before = self.sessions.presence_of(user_id);
+        let session = match self.sessions.login(session_id, user_id) {
+            Ok(session) => session,
+            Err(e) => {
+                self.reply_session_err(session_id, e);
+                return;
+            }
+        };
+        let resume_token = self.resume_tokens.issue(user_id, session_id);
+        session.resume_token = Some(resume_token.clone());
+        info!("User {} resumed on session {}", user_id, session_id);
+
+        let room_id = room_id.filter(|&room_id| self.enter_room(room_id, session_id));
+
+        // The session can only be back in one room, the others just get what they missed.
+        let mut others: Vec<(u128, Seq)> = last_seen
+            .iter()
+            .filter(|&(&other, _)| Some(other) != room_id)
+            .map(|(&other, &seq)| (other, seq))
+            .collect();
+        others.sort_unstable();
+        let (others, skipped): (Vec<_>, Vec<_>) = others.into_iter().partition(|&(other, _)| {
+            self.rooms
+                .get(other)
+                .is_ok_and(|room| room.is_reader(user_id) && room.check_banned(user_id).is_ok())
+        });
+
+        self.reply(
+            session_id,
+            ClientReply::Resumed {
+                user_id,
+                username,
+                display_name,
+                resume_token,
+                room_id,
+                skipped_rooms: skipped.into_iter().map(|(other, _)| other).collect(),
+            },
+        );
+
+        if let Some(room_id) = room_id {
+            self.send_members(room_id, session_id);
+            match last_seen.get(&room_id) {
+                Some(&seq) => self.replay(room_id, seq, session_id),
+                None => self.send_history(room_id, None, self.config.history_page, session_id),
+            }
+        }
+        for (other, seq) in others {
+            self.replay(other, seq, session_id);
+        }
+        self.deliver_pending(user_id, session_id);
+        self.presence_changed(user_id, before, None);
+    }
+
+    /// Sends the first page after `after`, oldest first. If there's more,
+    /// `next` says where the client's `FetchHistory` should carry on, rather
+    /// than pushing pages faster than the outbound queue can hold them.
+    fn replay(&mut self, room_id: u128, after: Seq, session_id: u128) {
+        self.send_history(
+            room_id,
+            Some(Cursor::After(after)),
+            self.config.max_history_page,
+            session_id,
+        );
+    }
+
+    fn join_room(&mut self, room_id: u128, session_id: u128) {
+        if self.enter_room(room_id, session_id) {
+            self.send_members(room_id, session_id);
+            self.send_history(room_id, None, self.config.history_page, session_id);
+        }
+    }
+
+    /// Moves the session into the room, or replies with why it can't.
+    fn enter_room(&mut self, room_id: u128, session_id: u128) -> bool {
+        let Some(session) = self.sessions.get_mut(session_id) else {
+            warn!("JoinRoom from unknown session {}", session_id);
+            return false;
+        };
+
+        // Only commit the transition once the room has taken us in.
+        let mut next = session.state;
+        if let Err(e) = next.join(room_id) {
+            self.reply_session_err(session_id, e);
+            return false;
+        }
+
+        if let Some(user_id) = next.user_id() {
+            let banned = self
+                .rooms
+                .get(room_id)
+                .and_then(|room| room.check_banned(user_id));
+            if let Err(e) = banned {
+                self.reply_room_err(session_id, e);
+                return false;
+            }
+        }
+
+        // Bots sit in their rooms without taking anyone's seat.
+        let seat = next
+            .user_id()
+            .filter(|&user_id| !self.users.get(user_id).is_some_and(|user| user.bot));
+        if let Err(e) = self.rooms.join(room_id, session_id, seat) {
+            self.reply_room_err(session_id, e);
+            return false;
+        }
+        session.state = next;
+        debug!("Session {} joined room {}", session_id, room_id);
+
+        let Some(user_id) = next.user_id() else {
+            return true;
+        };
+        if !self.user_present(room_id, user_id, session_id) {
+            self.member_joined(room_id, user_id);
+        }
+        let Some(last_read) = self
+            .rooms
+            .get_mut(room_id)
+            .ok()
+            .and_then(|room| room.add_reader(user_id))
+        else {
+            return true;
+        };
+        if let Err(e) = self.storage.add_member(room_id, user_id, last_read) {
+            error!(
+                "Failed to store membership of {} in {}: {}",
+                user_id, room_id, e
+            );
+        }
+        true
+    }
+
+    fn member_joined(&mut self, room_id: u128, user_id: u128) {
+        let Some(user) = self.users.get(user_id) else {
+            return;
+        };
+        let reply = ClientReply::MemberJoined {
+            room_id,
+            user_id,
+            display_name: user.display_name.clone(),
+            bot: user.bot,
+        };
+        for member in self.room_audience(room_id, user_id) {
+            self.reply(member, reply.clone());
+        }
+    }
+
+    fn send_message(
+        &mut self,
+        room_id: u128,
+        text: String,
+        parent_id: Option<u128>,
+        session_id: u128,
+    ) {
+        let text = match commands::parse(text) {
+            Ok(Input::Text(text)) => text,
+            Ok(Input::Command(command)) => {
+                self.run_command(command, room_id, session_id);
+                return;
+            }
+            Err(e) => {
+                self.reply_err(session_id, e.code(), e.to_string());
+                return;
+            }
+        };
+
+        let Some(session) = self.sessions.get(session_id) else {
+            warn!("SendMessage from unknown session {}", session_id);
+            return;
+        };
+
+        let user_id = match session.state.member_of(room_id) {
+            Ok(user_id) => user_id,
+            Err(e) => {
+                self.reply_session_err(session_id, e);
+                return;
+            }
+        };
+        self.deliver_message(room_id, user_id, text, parent_id, session_id);
+    }
+
+    /// Stores the message and fans it out to the room, with the ack going to
+    /// `session_id`, whether or not that session is in the room itself.
+    fn deliver_message(
+        &mut self,
+        room_id: u128,
+        user_id: u128,
+        text: String,
+        parent_id: Option<u128>,
+        session_id: u128,
+    ) {
+        let muted = self
+            .rooms
+            .get(room_id)
+            .and_then(|room| room.check_muted(user_id));
+        if let Err(e) = muted {
+            self.reply_room_err(session_id, e);
+            return;
+        }
+        let Some(text) = self.filter_text(Some(room_id), text, session_id) else {
+            return;
+        };
+
+        // Replying to a reply lands in the same thread.
+        let parent_id = match parent_id {
+            Some(parent_id) => match self.find_message(room_id, parent_id, session_id) {
+                Some(parent) => Some(parent.root_id()),
+                None => return,
+            },
+            None => None,
+        };
+
+        let username = self
+            .users
+            .get(user_id)
+            .map(|user| user.display_name.clone())
+            .unwrap_or_default();
+
+        let Ok(room) = self.rooms.get_mut(room_id) else {
+            warn!(
+                "Session {} is in room {} which doesn't exist",
+                session_id, room_id
+            );
+            return;
+        };
+
+        let message = room
+            .next_message(text, user_id, username)
+            .with_parent(parent_id);
+        if let Err(e) = self.storage.append_message(&message) {
+            self.reply_internal(session_id, e);
+            return;
+        }
+
+        let Ok(room) = self.rooms.get_mut(room_id) else {
+            return;
+        };
+        let message = Arc::new(message);
+        room.commit_message(&message);
+        let recipients: Vec<u128> = room
+            .members
+            .keys()
+            .copied()
+            .filter(|&member| member != session_id)
+            .collect();
+        debug!(
+            "Room {} stored message {}, fanning out to {} sessions",
+            room_id,
+            message.seq(),
+            recipients.len()
+        );
+
+        self.reply(
+            session_id,
+            ClientReply::MessageAck {
+                room_id,
+                message_id: message.id(),
+                seq: message.seq(),
+                time: message.time(),
+            },
+        );
+        for member in recipients {
+            self.reply(
+                member,
+                ClientReply::NewMessage {
+                    message: Arc::clone(&message),
+                },
+            );
+        }
+
+        // Nobody needs to be told they've read their own message.
+        let _ = self.advance_read(room_id, user_id, message.seq());
+
+        if let Some(root_id) = parent_id {
+            self.thread_updated(room_id, root_id, session_id);
+            self.notify_thread(&message, session_id);
+        }
+    }
+
+    fn fetch_thread(
+        &mut self,
+        room_id: u128,
+        root_id: u128,
+        cursor: Option<Cursor>,
+        limit: Option<u32>,
+        session_id: u128,
+    ) {
+        if self.require_member(room_id, session_id).is_none() {
+            return;
+        }
+
+        // A deleted root still holds its thread together.
+        let root = match self.storage.message(room_id, root_id) {
+            Ok(Some(root)) if root.parent_id().is_none() => root,
+            Ok(_) => {
+                let err = RoomError::MessageNotFound {
+                    message_id: root_id,
+                };
+                self.reply_room_err(session_id, err);
+                return;
+            }
+            Err(e) => {
+                self.reply_internal(session_id, e);
+                return;
+            }
+        };
+
+        let limit = limit
+            .map_or(self.config.history_page, |limit| limit as usize)
+            .clamp(1, self.config.max_history_page);
+        let root = match storage::annotate(self.storage.as_ref(), vec![root]) {
+            Ok(mut root) => root.remove(0),
+            Err(e) => {
+                self.reply_internal(session_id, e);
+                return;
+            }
+        };
+        match storage::thread_page(self.storage.as_ref(), room_id, root_id, cursor, limit) {
+            Ok((replies, next)) => self.reply(
+                session_id,
+                ClientReply::Thread {
+                    room_id,
+                    root,
+                    replies,
+                    next,
+                },
+            ),
+            Err(e) => self.reply_internal(session_id, e),
+        }
+    }
+
+    /// Tells the room the thread's reply count or last reply changed.
+    fn thread_updated(&mut self, room_id: u128, root_id: u128, session_id: u128) {
+        let thread = match self.storage.thread_summaries(&[root_id]) {
+            Ok(mut threads) => threads.remove(&root_id).unwrap_or(ThreadSummary {
+                reply_count: 0,
+                last_reply: None,
+            }),
+            Err(e) => {
+                self.reply_internal(session_id, e);
+                return;
+            }
+        };
+        self.broadcast(
+            room_id,
+            ClientReply::ThreadUpdated {
+                room_id,
+                root_id,
+                thread,
+            },
+        );
+    }
+
+    /// Passes a new reply on to everyone that took part in the thread but isn't
+    /// in the room to see it there.
+    fn notify_thread(&mut self, message: &Arc<UserTextMessage>, session_id: u128) {
+        let room_id = message.room_id();
+        let participants = match self.storage.thread_participants(room_id, message.root_id()) {
+            Ok(participants) => participants,
+            Err(e) => {
+                error!("Failed to load thread participants: {}", e);
+                return;
+            }
+        };
+
+        let sessions: Vec<u128> = participants
+            .into_iter()
+            .filter(|&user_id| user_id != message.from())
+            .flat_map(|user_id| self.sessions.sessions_of(user_id))
+            .filter(|&session| {
+                session != session_id
+                    && self
+                        .sessions
+                        .get(session)
+                        .is_some_and(|entry| entry.state.room_id() != Some(room_id))
+            })
+            .collect();
+        for session in sessions {
+            self.reply(
+                session,
+                ClientReply::ThreadReply {
+                    message: Arc::clone(message),
+                },
+            );
+        }
+    }
+
+    fn edit_message(&mut self, room_id: u128, message_id: u128, text: String, session_id: u128) {
+        let Some(user_id) = self.require_member(room_id, session_id) else {
+            return;
+        };
+        let Some(message) = self.find_message(room_id, message_id, session_id) else {
+            return;
+        };
+        if message.from() != user_id {
+            self.reply_room_err(session_id, RoomError::NotAuthor);
+            return;
+        }
+        if let Err(e) = self
+            .rooms
+            .get(room_id)
+            .and_then(|room| room.check_muted(user_id))
+        {
+            self.reply_room_err(session_id, e);
+            return;
+        }
+        let Some(text) = self.filter_text(Some(room_id), text, session_id) else {
+            return;
+        };
+
+        let (message, revision) = message.edit(text, Utc::now());
+        if let Err(e) = self.storage.edit_message(&message, &revision) {
+            self.reply_internal(session_id, e);
+            return;
+        }
+
+        let message = Arc::new(message);
+        if let Ok(room) = self.rooms.get_mut(room_id) {
+            room.replace_message(&message);
+        }
+        debug!("User {} edited message {}", user_id, message_id);
+        self.broadcast(room_id, ClientReply::MessageEdited { message });
+    }
+
+    fn delete_message(&mut self, room_id: u128, message_id: u128, session_id: u128) {
+        let Some(user_id) = self.require_member(room_id, session_id) else {
+            return;
+        };
+        let Some(message) = self.find_message(room_id, message_id, session_id) else {
+            return;
+        };
+        let is_moderator = self
+            .rooms
+            .get(room_id)
+            .is_ok_and(|room| room.is_moderator(user_id));
+        if message.from() != user_id && !is_moderator {
+            self.reply_room_err(session_id, RoomError::NotModerator);
+            return;
+        }
+
+        let tombstone = message.tombstone();
+        if let Err(e) = self.storage.delete_message(&tombstone) {
+            self.reply_internal(session_id, e);
+            return;
+        }
+
+        if let Ok(room) = self.rooms.get_mut(room_id) {
+            room.replace_message(&Arc::new(tombstone));
+        }
+        info!("User {} deleted message {}", user_id, message_id);
+        self.broadcast(
+            room_id,
+            ClientReply::MessageDeleted {
+                room_id,
+                message_id,
+                seq: message.seq(),
+            },
+        );
+        if let Some(root_id) = message.parent_id() {
+            self.thread_updated(room_id, root_id, session_id);
+        }
+    }
+
+    fn fetch_revisions(&mut self, room_id: u128, message_id: u128, session_id: u128) {
+        let Some(user_id) = self.require_login(session_id) else {
+            return;
+        };
+        match self.rooms.get(room_id) {
+            Ok(room) if room.is_moderator(user_id) => {}
+            Ok(_) => {
+                self.reply_room_err(session_id, RoomError::NotModerator);
+                return;
+            }
+            Err(e) => {
+                self.reply_room_err(session_id, e);
+                return;
+            }
+        }
+        if self.find_message(room_id, message_id, session_id).is_none() {
+            return;
+        }
+
+        match self.storage.revisions(message_id) {
+            Ok(revisions) => self.reply(
+                session_id,
+                ClientReply::Revisions {
+                    room_id,
+                    message_id,
+                    revisions,
+                },
+            ),
+            Err(e) => self.reply_internal(session_id, e),
+        }
+    }
+
+    /// Adds or removes one reaction on a message in the session's room.
+    fn react(&mut self, message_id: u128, emoji: &str, add: bool, session_id: u128) {
+        let Some(session) = self.sessions.get(session_id) else {
+            return;
+        };
+        let Some(room_id) = session.state.room_id() else {
+            let err = match session.state {
+                SessionState::Connected => SessionError::NotLoggedIn,
+                SessionState::Closing => SessionError::Closing,
+                _ => SessionError::NotInRoom,
+            };
+            self.reply_session_err(session_id, err);
+            return;
+        };
+        let Some(user_id) = session.state.user_id() else {
+            return;
+        };
+
+        let Some(emoji) = self.config.reactions.resolve(emoji) else {
+            let emoji = emoji.to_string();
+            self.reply_room_err(session_id, RoomError::InvalidReaction { emoji });
+            return;
+        };
+        if add {
+            if let Err(e) = self
+                .rooms
+                .get(room_id)
+                .and_then(|room| room.check_muted(user_id))
+            {
+                self.reply_room_err(session_id, e);
+                return;
+            }
+        }
+        if self.find_message(room_id, message_id, session_id).is_none() {
+            return;
+        }
+
+        let changed = if add {
+            self.storage.add_reaction(message_id, user_id, &emoji)
+        } else {
+            self.storage.remove_reaction(message_id, user_id, &emoji)
+        };
+        match changed {
+            Ok(true) => {}
+            // Reacting twice or taking back a reaction that isn't there changes nothing.
+            Ok(false) => return,
+            Err(e) => {
+                self.reply_internal(session_id, e);
+                return;
+            }
+        }
+
+        let reactions = match self.storage.reactions(&[message_id]) {
+            Ok(mut reactions) => reactions.remove(&message_id).unwrap_or_default(),
+            Err(e) => {
+                self.reply_internal(session_id, e);
+                return;
+            }
+        };
+
+        self.broadcast(
+            room_id,
+            ClientReply::ReactionsChanged {
+                room_id,
+                message_id,
+                reactions: reactions::summarize(&reactions),
+            },
+        );
+    }
+
+    /// A live message in the room, or an error reply. Tombstones count as gone.
+    fn find_message(
+        &mut self,
+        room_id: u128,
+        message_id: u128,
+        session_id: u128,
+    ) -> Option<Arc<UserTextMessage>> {
+        match self.storage.message(room_id, message_id) {
+            Ok(Some(message)) if !message.deleted() => Some(message),
+            Ok(_) => {
+                self.reply_room_err(session_id, RoomError::MessageNotFound { message_id });
+                None
+            }
+            Err(e) => {
+                self.reply_internal(session_id, e);
+                None
+            }
+        }
+    }
+
+    fn mark_read(&mut self, room_id: u128, up_to: Seq, session_id: u128) {
+        let Some(user_id) = self.require_login(session_id) else {
+            return;
+        };
+
+        let up_to = match self.advance_read(room_id, user_id, up_to) {
+            Ok(Some(up_to)) => up_to,
+            Ok(None) => return,
+            Err(e) => {
+                self.reply_room_err(session_id, e);
+                return;
+            }
+        };
+
+        // Other members see the receipt, the user's other sessions see their unread count drop.
+        let mut audience = self.room_audience(room_id, user_id);
+        audience.extend(self.sessions.sessions_of(user_id));
+        audience.retain(|&session| session != session_id);
+        for member in audience {
+            self.reply(
+                member,
+                ClientReply::ReadReceipt {
+                    room_id,
+                    user_id,
+                    up_to,
+                },
+            );
+        }
+    }
+
+    /// Moves the user's read marker in memory and in storage. `None` if it didn't move.
+    fn advance_read(
+        &mut self,
+        room_id: u128,
+        user_id: u128,
+        up_to: Seq,
+    ) -> Result<Option<Seq>, RoomError> {
+        let Some(up_to) = self.rooms.get_mut(room_id)?.mark_read(user_id, up_to)? else {
+            return Ok(None);
+        };
+        if let Err(e) = self.storage.set_last_read(room_id, user_id, up_to) {
+            error!(
+                "Failed to store read marker of {} in {}: {}",
+                user_id, room_id, e
+            );
+        }
+        Ok(Some(up_to))
+    }
+
+    fn my_rooms(&mut self, session_id: u128) {
+        let Some(user_id) = self.require_login(session_id) else {
+            return;
+        };
+
+        let rooms = self.rooms.summaries(user_id);
+        self.reply(session_id, ClientReply::MyRooms { rooms });
+    }
+
+    fn send_direct(&mut self, to_user_id: u128, text: String, session_id: u128) {
+        let Some(user_id) = self.require_login(session_id) else {
+            return;
+        };
+
+        if let Err(e) = self.users.find(to_user_id) {
+            self.reply_user_err(session_id, e);
+            return;
+        }
+        let Some(text) = self.filter_text(None, text, session_id) else {
+            return;
+        };
+        let username = self
+            .users
+            .get(user_id)
+            .map(|user| user.display_name.clone())
+            .unwrap_or_default();
+
+        let message = self
+            .direct
+            .next_message(text, user_id, to_user_id, username);
+        let recipients = self.sessions.sessions_of(to_user_id);
+        let delivered = !recipients.is_empty();
+        if let Err(e) = self.storage.append_direct(&message, delivered) {
+            self.reply_internal(session_id, e);
+            return;
+        }
+        self.direct.commit_message(&message);
+        let message = Arc::new(message);
+
+        // The sender's other sessions get a copy too so every device sees the conversation.
+        let mut copies = self.sessions.sessions_of(user_id);
+        copies.extend(recipients);
+        copies.sort_unstable();
+        copies.dedup();
+        copies.retain(|&session| session != session_id);
+        debug!(
+            "Direct message {} from {} to {}, {} live sessions",
+            message.id(),
+            user_id,
+            to_user_id,
+            copies.len()
+        );
+
+        self.reply(
+            session_id,
+            ClientReply::DirectAck {
+                to_user_id,
+                message_id: message.id(),
+                seq: message.seq(),
+                time: message.time(),
+            },
+        );
+        for session in copies {
+            self.reply(
+                session,
+                ClientReply::NewDirect {
+                    message: Arc::clone(&message),
+                },
+            );
+        }
+    }
+
+    fn fetch_direct_history(
+        &mut self,
+        other_user_id: u128,
+        cursor: Option<Cursor>,
+        limit: Option<u32>,
+        session_id: u128,
+    ) {
+        let Some(user_id) = self.require_login(session_id) else {
+            return;
+        };
+
+        if let Err(e) = self.users.find(other_user_id) {
+            self.reply_user_err(session_id, e);
+            return;
+        }
+
+        let limit = limit
+            .map_or(self.config.history_page, |limit| limit as usize)
+            .clamp(1, self.config.max_history_page);
+        let pair = Pair::new(user_id, other_user_id);
+        match storage::direct_page(self.storage.as_ref(), pair, cursor, limit) {
+            Ok((messages, next)) => self.reply(
+                session_id,
+                ClientReply::DirectHistory {
+                    user_id: other_user_id,
+                    messages,
+                    next,
+                },
+            ),
+            Err(e) => self.reply_internal(session_id, e),
+        }
+    }
+
+    /// Hands over direct messages that arrived while the user had no session open.
+    fn deliver_pending(&mut self, user_id: u128, session_id: u128) {
+        let pending = match self.storage.pending_direct(user_id) {
+            Ok(pending) => pending,
+            Err(e) => {
+                self.reply_internal(session_id, e);
+                return;
+            }
+        };
+        if pending.is_empty() {
+            return;
+        }
+
+        // Only the first page of each conversation goes out, the same way
+        // `replay` does rooms, so a long absence can't overflow the queue.
+        let mut pages = Vec::with_capacity(pending.len());
+        for (from, oldest) in pending {
+            let pair = Pair::new(user_id, from);
+            let cursor = Some(Cursor::After(oldest - 1));
+            let limit = self.config.history_page;
+            match storage::direct_page(self.storage.as_ref(), pair, cursor, limit) {
+                Ok((messages, next)) => pages.push((from, messages, next)),
+                Err(e) => {
+                    self.reply_internal(session_id, e);
+                    return;
+                }
+            }
+        }
+        if let Err(e) = self.storage.mark_direct_delivered(user_id) {
+            self.reply_internal(session_id, e);
+            return;
+        }
+
+        debug!(
+            "Delivering pending direct messages from {} users to {}",
+            pages.len(),
+            user_id
+        );
+        for (from, messages, next) in pages {
+            self.reply(
+                session_id,
+                ClientReply::PendingDirect {
+                    user_id: from,
+                    messages,
+                    next,
+                },
+            );
+        }
+    }
+
+    fn fetch_history(
+        &mut self,
+        room_id: u128,
+        cursor: Option<Cursor>,
+        limit: Option<u32>,
+        session_id: u128,
+    ) {
+        let Some(session) = self.sessions.get(session_id) else {
+            return;
+        };
+
+        if let Err(e) = session.state.member_of(room_id) {
+            self.reply_session_err(session_id, e);
+            return;
+        }
+
+        let limit = limit
+            .map_or(self.config.history_page, |limit| limit as usize)
+            .clamp(1, self.config.max_history_page);
+        self.send_history(room_id, cursor, limit, session_id);
+    }
+
+    fn send_history(
+        &mut self,
+        room_id: u128,
+        cursor: Option<Cursor>,
+        limit: usize,
+        session_id: u128,
+    ) {
+        match storage::history_page(self.storage.as_ref(), room_id, cursor, limit) {
+            Ok((messages, next)) => self.reply(
+                session_id,
+                ClientReply::History {
+                    room_id,
+                    messages,
+                    next,
+                },
+            ),
+            Err(e) => self.reply_internal(session_id, e),
+        }
+    }
+
+    /// Runs the text through the room's filters, or the default ones outside
+    /// a room, and replies with the reason if they turn it down.
+    fn filter_text(
+        &mut self,
+        room_id: Option<u128>,
+        text: String,
+        session_id: u128,
+    ) -> Option<String> {
+        let chain = match room_id {
+            Some(room_id) => self.config.filters.for_room(room_id),
+            None => self.config.filters.default_chain(),
+        };
+        match chain.run(text) {
+            Ok(text) => Some(text),
+            Err(e) => {
+                debug!("Session {} had a message rejected: {}", session_id, e);
+                self.reply_err(session_id, e.code(), e.to_string());
+                None
+            }
+        }
+    }
+
+    /// What `/me` turns `action` into.
+    fn action_text(&self, user_id: u128, action: &str) -> String {
+        let display_name = self
+            .users
+            .get(user_id)
+            .map(|user| user.display_name.as_str())
+            .unwrap_or_default();
+        format!("* {} {}", display_name, action)
+    }
+
+    /// Carries out a slash command typed into `room_id`.
+    fn run_command(&mut self, command: Command, room_id: u128, session_id: u128) {
+        if self.require_login(session_id).is_none() {
+            return;
+        }
+
+        match command {
+            Command::Join { room } => {
+                // Typed as a message, so it only got charged as one so far.
+                if self.check_rate(Action::Join, session_id) {
+                    self.switch_room(&room, session_id);
+                }
+            }
+            Command::Leave => self.leave_room(session_id),
+            Command::Nick { display_name } => self.change_display_name(&display_name, session_id),
+            Command::Me { action } => {
+                let Some(user_id) = self.require_member(room_id, session_id) else {
+                    return;
+                };
+                let text = self.action_text(user_id, &action);
+                self.send_message(room_id, text, None, session_id);
+            }
+            Command::Who => {
+                if self.require_member(room_id, session_id).is_some() {
+                    self.send_members(room_id, session_id);
+                }
+            }
+            Command::Away => self.set_away(true, session_id),
+            Command::Back => self.set_away(false, session_id),
+            Command::Help { command } => match commands::help(command.as_deref()) {
+                Ok(text) => self.reply(session_id, ClientReply::Notice { text }),
+                Err(e) => self.reply_err(session_id, e.code(), e.to_string()),
+            },
+            Command::Kick { username, reason } => {
+                if let Some(user_id) = self.lookup_username(&username, session_id) {
+                    self.kick(room_id, user_id, reason, session_id);
+                }
+            }
+            Command::Ban {
+                username,
+                duration_secs,
+                reason,
+            } => {
+                if let Some(user_id) = self.lookup_username(&username, session_id) {
+                    self.ban(room_id, user_id, duration_secs, reason, session_id);
+                }
+            }
+            Command::Unban { username } => {
+                if let Some(user_id) = self.lookup_username(&username, session_id) {
+                    self.unban(room_id, user_id, session_id);
+                }
+            }
+            Command::Mute {
+                username,
+                duration_secs,
+            } => {
+                if let Some(user_id) = self.lookup_username(&username, session_id) {
+                    self.mute(room_id, user_id, duration_secs, session_id);
+                }
+            }
+            Command::Unmute { username } => {
+                if let Some(user_id) = self.lookup_username(&username, session_id) {
+                    self.unmute(room_id, user_id, session_id);
+                }
+            }
+            Command::Mod {
+                username,
+                moderator,
+            } => {
+                if let Some(user_id) = self.lookup_username(&username, session_id) {
+                    let role = if moderator {
+                        Role::Moderator
+                    } else {
+                        Role::Member
+                    };
+                    self.set_role(room_id, user_id, role, session_id);
+                }
+            }
+        }
+    }
+
+    fn lookup_username(&mut self, username: &str, session_id: u128) -> Option<u128> {
+        match self.users.find_by_username(username) {
+            Ok(user) => Some(user.id),
+            Err(e) => {
+                self.reply_user_err(session_id, e);
+                None
+            }
+        }
+    }
+
+    fn set_role(&mut self, room_id: u128, user_id: u128, role: Role, session_id: u128) {
+        let Some(by) = self.require_member(room_id, session_id) else {
+            return;
+        };
+        if let Err(e) = self.users.find(user_id) {
+            self.reply_user_err(session_id, e);
+            return;
+        }
+        let allowed = self
+            .rooms
+            .get(room_id)
+            .and_then(|room| room.check_set_role(by, user_id, role));
+        if let Err(e) = allowed {
+            self.reply_room_err(session_id, e);
+            return;
+        }
+
+        let moderator = role == Role::Moderator;
+        if let Err(e) = self.storage.set_moderator(room_id, user_id, moderator) {
+            self.reply_internal(session_id, e);
+            return;
+        }
+        if let Ok(room) = self.rooms.get_mut(room_id) {
+            room.set_moderator(user_id, moderator);
+        }
+        info!(
+            "User {} made {} a {:?} of room {}",
+            by, user_id, role, room_id
+        );
+        self.room_event(
+            room_id,
+            user_id,
+            RoomEvent::RoleChanged { user_id, role, by },
+        );
+    }
+
+    fn kick(&mut self, room_id: u128, user_id: u128, reason: Option<String>, session_id: u128) {
+        let Some(by) = self.require_moderator(room_id, user_id, session_id) else {
+            return;
+        };
+
+        self.expel(room_id, user_id);
+        info!("User {} kicked {} from room {}", by, user_id, room_id);
+        self.room_event(
+            room_id,
+            user_id,
+            RoomEvent::Kicked {
+                user_id,
+                by,
+                reason,
+            },
+        );
+    }
+
+    fn ban(
+        &mut self,
+        room_id: u128,
+        user_id: u128,
+        duration_secs: Option<u64>,
+        reason: Option<String>,
+        session_id: u128,
+    ) {
+        if duration_secs == Some(0) {
+            self.reply_room_err(session_id, RoomError::InvalidDuration);
+            return;
+        }
+        let Some(by) = self.require_moderator(room_id, user_id, session_id) else {
+            return;
+        };
+
+        let ban = StoredBan {
+            room_id,
+            user_id,
+            by,
+            until: duration_secs.and_then(until),
+            reason,
+        };
+        if let Err(e) = self.storage.ban(&ban) {
+            self.reply_internal(session_id, e);
+            return;
+        }
+        if let Ok(room) = self.rooms.get_mut(room_id) {
+            room.ban(user_id, ban.until);
+        }
+
+        self.expel(room_id, user_id);
+        info!("User {} banned {} from room {}", by, user_id, room_id);
+        self.room_event(
+            room_id,
+            user_id,
+            RoomEvent::Banned {
+                user_id,
+                by,
+                until: ban.until,
+                reason: ban.reason,
+            },
+        );
+    }
+
+    fn unban(&mut self, room_id: u128, user_id: u128, session_id: u128) {
+        let Some(by) = self.require_moderator(room_id, user_id, session_id) else {
+            return;
+        };
+
+        if let Err(e) = self.storage.unban(room_id, user_id) {
+            self.reply_internal(session_id, e);
+            return;
+        }
+        let lifted = self
+            .rooms
+            .get_mut(room_id)
+            .is_ok_and(|room| room.unban(user_id));
+        if !lifted {
+            let text = format!("{} isn't banned", self.display_name_of(user_id));
+            self.reply(session_id, ClientReply::Notice { text });
+            return;
+        }
+        info!("User {} unbanned {} from room {}", by, user_id, room_id);
+        self.room_event(room_id, user_id, RoomEvent::Unbanned { user_id, by });
+    }
+
+    fn mute(&mut self, room_id: u128, user_id: u128, duration_secs: Option<u64>, session_id: u128) {
+        if duration_secs == Some(0) {
+            self.reply_room_err(session_id, RoomError::InvalidDuration);
+            return;
+        }
+        let Some(by) = self.require_moderator(room_id, user_id, session_id) else {
+            return;
+        };
+
+        let mute = StoredMute {
+            room_id,
+            user_id,
+            by,
+            until: duration_secs.and_then(until),
+        };
+        if let Err(e) = self.storage.mute(&mute) {
+            self.reply_internal(session_id, e);
+            return;
+        }
+        if let Ok(room) = self.rooms.get_mut(room_id) {
+            room.mute(user_id, mute.until);
+        }
+        info!("User {} muted {} in room {}", by, user_id, room_id);
+        self.room_event(
+            room_id,
+            user_id,
+            RoomEvent::Muted {
+                user_id,
+                by,
+                until: mute.until,
+            },
+        );
+    }
+
+    fn unmute(&mut self, room_id: u128, user_id: u128, session_id: u128) {
+        let Some(by) = self.require_moderator(room_id, user_id, session_id) else {
+            return;
+        };
+
+        if let Err(e) = self.storage.unmute(room_id, user_id) {
+            self.reply_internal(session_id, e);
+            return;
+        }
+        let lifted = self
+            .rooms
+            .get_mut(room_id)
+            .is_ok_and(|room| room.unmute(user_id));
+        if !lifted {
+            let text = format!("{} isn't muted", self.display_name_of(user_id));
+            self.reply(session_id, ClientReply::Notice { text });
+            return;
+        }
+        info!("User {} unmuted {} in room {}", by, user_id, room_id);
+        self.room_event(room_id, user_id, RoomEvent::Unmuted { user_id, by });
+    }
+
+    /// The moderator behind a session in the room, if they may act on
+    /// `user_id`, or an error reply.
+    fn require_moderator(
+        &mut self,
+        room_id: u128,
+        user_id: u128,
+        session_id: u128,
+    ) -> Option<u128> {
+        let by = self.require_member(room_id, session_id)?;
+        if let Err(e) = self.users.find(user_id) {
+            self.reply_user_err(session_id, e);
+            return None;
+        }
+        let allowed = self
+            .rooms
+            .get(room_id)
+            .and_then(|room| room.check_moderate(by, user_id));
+        if let Err(e) = allowed {
+            self.reply_room_err(session_id, e);
+            return None;
+        }
+        Some(by)
+    }
+
+    /// Takes every session of the user out of the room and ends their membership.
+    fn expel(&mut self, room_id: u128, user_id: u128) {
+        for session_id in self.sessions.sessions_of(user_id) {
+            let Some(session) = self.sessions.get_mut(session_id) else {
+                continue;
+            };
+            if session.state.room_id() == Some(room_id) && session.state.leave().is_ok() {
+                self.remove_member(room_id, session_id, Some(user_id));
+            }
+        }
+
+        if let Ok(room) = self.rooms.get_mut(room_id) {
+            room.remove_reader(user_id);
+        }
+        if let Err(e) = self.storage.remove_member(room_id, user_id) {
+            error!(
+                "Failed to remove membership of {} in {}: {}",
+                user_id, room_id, e
+            );
+        }
+    }
+
+    /// Tells the room, and the user it happened to wherever they are, what a
+    /// moderator did.
+    fn room_event(&mut self, room_id: u128, user_id: u128, event: RoomEvent) {
+        let text = self.describe(&event);
+        let mut audience = self.sessions.sessions_of(user_id);
+        if let Ok(room) = self.rooms.get(room_id) {
+            audience.extend(room.members.keys().copied());
+        }
+        audience.sort_unstable();
+        audience.dedup();
+
+        for member in audience {
+            self.reply(
+                member,
+                ClientReply::RoomEvent {
+                    room_id,
+                    event: event.clone(),
+                    text: text.clone(),
+                },
+            );
+        }
+    }
+
+    fn describe(&self, event: &RoomEvent) -> String {
+        let name = |user_id| self.display_name_of(user_id);
+        let ending = |until: &Option<Time>| match until {
+            Some(until) => format!(" until {}", until.format("%Y-%m-%d %H:%M UTC")),
+            None => String::new(),
+        };
+        let reason = |reason: &Option<String>| match reason {
+            Some(reason) => format!(": {}", reason),
+            None => String::new(),
+        };
+
+        match event {
+            RoomEvent::RoleChanged { user_id, role, by } => {
+                let role = match role {
+                    Role::Moderator => "a moderator",
+                    Role::Member | Role::Owner => "a regular member",
+                };
+                format!("{} made {} {}", name(*by), name(*user_id), role)
+            }
+            RoomEvent::Kicked {
+                user_id,
+                by,
+                reason: why,
+            } => format!(
+                "{} was kicked by {}{}",
+                name(*user_id),
+                name(*by),
+                reason(why)
+            ),
+            RoomEvent::Banned {
+                user_id,
+                by,
+                until: end,
+                reason: why,
+            } => format!(
+                "{} was banned by {}{}{}",
+                name(*user_id),
+                name(*by),
+                ending(end),
+                reason(why)
+            ),
+            RoomEvent::Unbanned { user_id, by } => {
+                format!("{} was unbanned by {}", name(*user_id), name(*by))
+            }
+            RoomEvent::Muted {
+                user_id,
+                by,
+                until: end,
+            } => format!(
+                "{} was muted by {}{}",
+                name(*user_id),
+                name(*by),
+                ending(end)
+            ),
+            RoomEvent::Unmuted { user_id, by } => {
+                format!("{} was unmuted by {}", name(*user_id), name(*by))
+            }
+        }
+    }
+
+    fn display_name_of(&self, user_id: u128) -> String {
+        self.users
+            .get(user_id)
+            .map(|user| user.display_name.clone())
+            .unwrap_or_default()
+    }
+
+    /// `/join`: steps out of the current room, keeping the membership, and
+    /// into the one with that name.
+    fn switch_room(&mut self, name: &str, session_id: u128) {
+        let room_id = match self.rooms.find_by_name(name) {
+            Ok(room) => room.id,
+            Err(e) => {
+                self.reply_room_err(session_id, e);
+                return;
+            }
+        };
+
+        let Some(session) = self.sessions.get_mut(session_id) else {
+            return;
+        };
+        if let SessionState::InRoom {
+            user_id,
+            room_id: current,
+        } = session.state
+        {
+            if current == room_id {
+                return;
+            }
+            if session.state.leave().is_ok() {
+                self.remove_member(current, session_id, Some(user_id));
+            }
+        }
+        self.join_room(room_id, session_id);
+    }
+
+    /// `/nick`: renames the user everywhere and tells whoever can see them.
+    fn change_display_name(&mut self, display_name: &str, session_id: u128) {
+        let Some(user_id) = self.require_login(session_id) else {
+            return;
+        };
+
+        let display_name = match self.users.check_display_name(display_name) {
+            Ok(display_name) => display_name,
+            Err(e) => {
+                self.reply_user_err(session_id, e);
+                return;
+            }
+        };
+        if let Err(e) = self.storage.set_display_name(user_id, &display_name) {
+            self.reply_internal(session_id, e);
+            return;
+        }
+        self.users.set_display_name(user_id, display_name.clone());
+
+        let mut audience = self.sessions.sessions_of(user_id);
+        for room in self.rooms.summaries(user_id) {
+            audience.extend(self.room_audience(room.room.id, user_id));
+        }
+        audience.sort_unstable();
+        audience.dedup();
+        for member in audience {
+            self.reply(
+                member,
+                ClientReply::DisplayNameChanged {
+                    user_id,
+                    display_name: display_name.clone(),
+                },
+            );
+        }
+    }
+
+    /// Everyone with a membership in the room and whether they are around.
+    fn send_members(&mut self, room_id: u128, session_id: u128) {
+        let user_ids = match self.storage.members(room_id) {
+            Ok(user_ids) => user_ids,
+            Err(e) => {
+                self.reply_internal(session_id, e);
+                return;
+            }
+        };
+
+        let mut members: Vec<MemberInfo> = user_ids
+            .into_iter()
+            .filter_map(|user_id| {
+                let user = self.users.get(user_id)?;
+                let presence = self.sessions.presence_of(user_id);
+                Some(MemberInfo {
+                    user_id,
+                    display_name: user.display_name.clone(),
+                    presence,
+                    last_seen: self.last_seen_of(user_id, presence),
+                    role: self
+                        .rooms
+                        .get(room_id)
+                        .map_or(Role::Member, |room| room.role_of(user_id)),
+                    bot: user.bot,
+                })
+            })
+            .collect();
+        members.sort_by(|a, b| a.display_name.cmp(&b.display_name));
+        self.reply(session_id, ClientReply::Members { room_id, members });
+    }
+
+    fn set_away(&mut self, away: bool, session_id: u128) {
+        let Some(user_id) = self.require_login(session_id) else {
+            return;
+        };
+
+        let before = self.sessions.presence_of(user_id);
+        if let Some(session) = self.sessions.get_mut(session_id) {
+            session.away = away;
+        }
+        self.presence_changed(user_id, before, None);
+    }
+
+    fn typing(&mut self, room_id: u128, session_id: u128) {
+        let Some(session) = self.sessions.get_mut(session_id) else {
+            return;
+        };
+
+        let user_id = match session.state.member_of(room_id) {
+            Ok(user_id) => user_id,
+            Err(e) => {
+                self.reply_session_err(session_id, e);
+                return;
+            }
+        };
+        // Typing would only announce a message a muted user can't send.
+        // Dropped quietly, an error per keystroke would only be noise.
+        if self
+            .rooms
+            .get(room_id)
+            .and_then(|room| room.check_muted(user_id))
+            .is_err()
+        {
+            return;
+        }
+
+        // Clients send these on every keystroke, only pass one on per window.
+        let now = Instant::now();
+        if session
+            .last_typing
+            .is_some_and(|last| now.duration_since(last) < self.config.typing_throttle)
+        {
+            return;
+        }
+        session.last_typing = Some(now);
+
+        let display_name = self
+            .users
+            .get(user_id)
+            .map(|user| user.display_name.clone())
+            .unwrap_or_default();
+        let expires_in_ms = self.config.typing_ttl.as_millis() as u64;
+        for member in self.room_audience(room_id, user_id) {
+            self.reply(
+                member,
+                ClientReply::Typing {
+                    room_id,
+                    user_id,
+                    display_name: display_name.clone(),
+                    expires_in_ms,
+                },
+            );
+        }
+    }
+
+    /// Tells everyone sharing a room with the user if their presence moved
+    /// away from `before`. `left_room` covers a room the user just dropped out of.
+    fn presence_changed(&mut self, user_id: u128, before: Presence, left_room: Option<u128>) {
+        let presence = self.sessions.presence_of(user_id);
+        if presence == before {
+            return;
+        }
+
+        let mut rooms: Vec<u128> = self
+            .sessions
+            .sessions_of(user_id)
+            .into_iter()
+            .filter_map(|session_id| self.sessions.get(session_id)?.state.room_id())
+            .chain(left_room)
+            .collect();
+        rooms.sort_unstable();
+        rooms.dedup();
+
+        let mut audience: Vec<u128> = rooms
+            .into_iter()
+            .flat_map(|room_id| self.room_audience(room_id, user_id))
+            .collect();
+        audience.sort_unstable();
+        audience.dedup();
+
+        let last_seen = self.last_seen_of(user_id, presence);
+        debug!(
+            "User {} is now {:?}, telling {} sessions",
+            user_id,
+            presence,
+            audience.len()
+        );
+        for member in audience {
+            self.reply(
+                member,
+                ClientReply::PresenceChanged {
+                    user_id,
+                    presence,
+                    last_seen,
+                },
+            );
+        }
+    }
+
+    /// Sessions in the room that don't belong to `user_id`.
+    fn room_audience(&self, room_id: u128, user_id: u128) -> Vec<u128> {
+        let Ok(room) = self.rooms.get(room_id) else {
+            return Vec::new();
+        };
+        room.members
+            .keys()
+            .copied()
+            .filter(|&member| {
+                self.sessions
+                    .get(member)
+                    .is_some_and(|session| session.state.user_id() != Some(user_id))
+            })
+            .collect()
+    }
+
+    fn last_seen_of(&self, user_id: u128, presence: Presence) -> Option<Time> {
+        match presence {
+            Presence::Offline => self.last_seen.get(&user_id).copied(),
+            Presence::Online | Presence::Away => None,
+        }
+    }
+
+    fn record_last_seen(&mut self, user_id: u128) {
+        let now = Utc::now();
+        if let Err(e) = self.storage.set_last_seen(user_id, now) {
+            error!("Failed to store last seen of {}: {}", user_id, e);
+        }
+        self.last_seen.insert(user_id, now);
+    }
+
+    fn leave_room(&mut self, session_id: u128) {
+        let Some(session) = self.sessions.get_mut(session_id) else {
+            return;
+        };
+
+        let user_id = session.state.user_id();
+        match session.state.leave() {
+            Ok(room_id) => {
+                self.remove_member(room_id, session_id, user_id);
+                debug!("Session {} left room {}", session_id, room_id);
+
+                // Leaving on purpose ends the membership, unlike disconnecting.
+                if let Some(user_id) = user_id {
+                    if let Ok(room) = self.rooms.get_mut(room_id) {
+                        room.remove_reader(user_id);
+                    }
+                    if let Err(e) = self.storage.remove_member(room_id, user_id) {
+                        error!(
+                            "Failed to remove membership of {} in {}: {}",
+                            user_id, room_id, e
+                        );
+                    }
+                }
+            }
+            Err(e) => self.reply_session_err(session_id, e),
+        }
+    }
+
+    /// Takes the session out of the room. Once the user's last session is
+    /// gone the room is told they left.
+    fn remove_member(&mut self, room_id: u128, session_id: u128, user_id: Option<u128>) {
+        self.rooms.leave(room_id, session_id);
+        let Some(user_id) = user_id else {
+            return;
+        };
+        if !self.user_present(room_id, user_id, session_id) {
+            self.broadcast(room_id, ClientReply::MemberLeft { room_id, user_id });
+        }
+    }
+
+    /// Whether any of the user's sessions other than `except` is in the room.
+    fn user_present(&self, room_id: u128, user_id: u128, except: u128) -> bool {
+        self.rooms.get(room_id).is_ok_and(|room| {
+            room.members.keys().any(|&member| {
+                member != except
+                    && self
+                        .sessions
+                        .get(member)
+                        .is_some_and(|session| session.state.user_id() == Some(user_id))
+            })
+        })
+    }
+
+    fn create_room(&mut self, name: &str, capacity: u32, session_id: u128) {
+        let Some(user_id) = self.require_login(session_id) else {
+            return;
+        };
+
+        let room = match self.rooms.new_room(name, capacity, user_id) {
+            Ok(room) => room,
+            Err(e) => {
+                self.reply_room_err(session_id, e);
+                return;
+            }
+        };
+
+        if let Err(e) = self.storage.insert_room(&room.stored()) {
+            self.reply_internal(session_id, e);
+            return;
+        }
+
+        let room = self.rooms.insert(room).info();
+        info!(
+            "Session {} created room {} ({})",
+            session_id, room.id, room.name
+        );
+        self.reply(session_id, ClientReply::RoomCreated { room });
+    }
+
+    fn list_rooms(&mut self, session_id: u128) {
+        if self.require_login(session_id).is_none() {
+            return;
+        }
+
+        let rooms = self.rooms.list();
+        self.reply(session_id, ClientReply::Rooms { rooms });
+    }
+
+    fn rename_room(&mut self, room_id: u128, name: &str, session_id: u128) {
+        let Some(user_id) = self.require_login(session_id) else {
+            return;
+        };
+
+        let checked = self
+            .rooms
+            .get(room_id)
+            .and_then(|room| room.check_moderator(user_id))
+            .and_then(|()| self.rooms.check_rename(room_id, name));
+        let name = match checked {
+            Ok(name) => name,
+            Err(e) => {
+                self.reply_room_err(session_id, e);
+                return;
+            }
+        };
+
+        if let Err(e) = self.storage.rename_room(room_id, &name) {
+            self.reply_internal(session_id, e);
+            return;
+        }
+
+        match self.rooms.rename(room_id, &name) {
+            Ok(room) => {
+                let room = room.info();
+                self.reply(session_id, ClientReply::RoomRenamed { room });
+            }
+            Err(e) => self.reply_room_err(session_id, e),
+        }
+    }
+
+    fn delete_room(&mut self, room_id: u128, session_id: u128) {
+        let Some(user_id) = self.require_login(session_id) else {
+            return;
+        };
+
+        let members: Vec<u128> = match self.rooms.get(room_id) {
+            Ok(room) => match room.check_owner(user_id) {
+                Ok(()) => room.members.keys().copied().collect(),
+                Err(e) => {
+                    self.reply_room_err(session_id, e);
+                    return;
+                }
+            },
+            Err(e) => {
+                self.reply_room_err(session_id, e);
+                return;
+            }
+        };
+
+        if let Err(e) = self.storage.delete_room(room_id) {
+            self.reply_internal(session_id, e);
+            return;
+        }
+
+        // Everyone leaves the way they would on their own, so the ones still
+        // in the room hear about it before it goes.
+        for &member in &members {
+            let Some(session) = self.sessions.get_mut(member) else {
+                continue;
+            };
+            let user_id = session.state.user_id();
+            if session.state.leave().is_ok() {
+                self.remove_member(room_id, member, user_id);
+            }
+        }
+
+        let Ok(room) = self.rooms.delete(room_id) else {
+            return;
+        };
+
+        info!(
+            "Session {} deleted room {} ({})",
+            session_id, room.id, room.name
+        );
+        for member in members {
+            if member != session_id {
+                self.reply(member, ClientReply::RoomDeleted { room_id });
+            }
+        }
+        self.reply(session_id, ClientReply::RoomDeleted { room_id });
+    }
+
+    fn token_login(&mut self, token: &str, session_id: u128) {
+        let user_id = self
+            .config
+            .api_tokens
+            .get(token)
+            .and_then(|username| self.users.find_by_username(username).ok())
+            .map(|user| user.id);
+        let Some(user_id) = user_id else {
+            self.reply_err(
+                session_id,
+                ErrorCode::InvalidToken,
+                "Unknown API token".to_string(),
+            );
+            return;
+        };
+
+        let session = match self.sessions.login(session_id, user_id) {
+            Ok(session) => session,
+            Err(e) => {
+                self.reply_session_err(session_id, e);
+                return;
+            }
+        };
+        session.api = true;
+        debug!("Session {} logged in as {} by token", session_id, user_id);
+    }
+
+    /// Posts like `SendMessage` would, except that `/me` is the only command
+    /// that makes sense without a connection to answer on.
+    fn post_message(&mut self, room_id: u128, text: String, session_id: u128) {
+        let Some(user_id) = self.require_login(session_id) else {
+            return;
+        };
+        let text = match commands::parse(text) {
+            Ok(Input::Text(text)) => text,
+            Ok(Input::Command(Command::Me { action })) => self.action_text(user_id, &action),
+            Ok(Input::Command(_)) => {
+                self.reply_err(
+                    session_id,
+                    ErrorCode::InvalidCommand,
+                    "Only /me works over the HTTP API".to_string(),
+                );
+                return;
+            }
+            Err(e) => {
+                self.reply_err(session_id, e.code(), e.to_string());
+                return;
+            }
+        };
+
+        let banned = self
+            .rooms
+            .get(room_id)
+            .and_then(|room| room.check_banned(user_id));
+        if let Err(e) = banned {
+            self.reply_room_err(session_id, e);
+            return;
+        }
+        self.deliver_message(room_id, user_id, text, None, session_id);
+    }
+
+    fn read_history(
+        &mut self,
+        room_id: u128,
+        cursor: Option<Cursor>,
+        limit: Option<u32>,
+        session_id: u128,
+    ) {
+        let Some(user_id) = self.require_login(session_id) else {
+            return;
+        };
+
+        let banned = self
+            .rooms
+            .get(room_id)
+            .and_then(|room| room.check_banned(user_id));
+        if let Err(e) = banned {
+            self.reply_room_err(session_id, e);
+            return;
+        }
+
+        let limit = limit
+            .map_or(self.config.history_page, |limit| limit as usize)
+            .clamp(1, self.config.max_history_page);
+        self.send_history(room_id, cursor, limit, session_id);
+    }
+
+    fn find_user(&mut self, username: &str, session_id: u128) {
+        if self.require_login(session_id).is_none() {
+            return;
+        }
+
+        let user = match self.users.find_by_username(username) {
+            Ok(user) => user,
+            Err(e) => {
+                self.reply_user_err(session_id, e);
+                return;
+            }
+        };
+        let presence = self.sessions.presence_of(user.id);
+        let user = UserInfo {
+            user_id: user.id,
+            username: user.username.clone(),
+            display_name: user.display_name.clone(),
+            presence,
+            last_seen: self.last_seen_of(user.id, presence),
+            bot: user.bot,
+        };
+        self.reply(session_id, ClientReply::User { user });
+    }
+
+    /// The user behind a session that is in `room_id`, or an error reply.
+    fn require_member(&mut self, room_id: u128, session_id: u128) -> Option<u128> {
+        let session = self.sessions.get(session_id)?;
+        match session.state.member_of(room_id) {
+            Ok(user_id) => Some(user_id),
+            Err(e) => {
+                self.reply_session_err(session_id, e);
+                None
+            }
+        }
+    }
+
+    /// Sends the reply to every session in the room.
+    fn broadcast(&mut self, room_id: u128, reply: ClientReply) {
+        let Ok(room) = self.rooms.get(room_id) else {
+            return;
+        };
+        let members: Vec<u128> = room.members.keys().copied().collect();
+        for member in members {
+            self.reply(member, reply.clone());
+        }
+    }
+
+    /// The user behind a session, or an error reply if it hasn't logged in yet.
+    fn require_login(&mut self, session_id: u128) -> Option<u128> {
+        let state = self.sessions.get(session_id)?.state;
+        if state.user_id().is_none() {
+            let err = match state {
+                SessionState::Closing => SessionError::Closing,
+                _ => SessionError::NotLoggedIn,
+            };
+            self.reply_session_err(session_id, err);
+        }
+        state.user_id()
+    }
+
+    fn reply(&mut self, session_id: u128, reply: ClientReply) {
+        match self.sessions.send(session_id, reply) {
+            PushResult::Queued => {}
+            PushResult::Dropped { missed } => {
+                debug!(
+                    "Session {} is behind, {} replies dropped",
+                    session_id, missed
+                );
+            }
+            PushResult::Overflowed => {
+                warn!("Session {} fell too far behind, disconnecting", session_id);
+                self.disconnect(session_id);
+            }
+            PushResult::Closed => {
+                // The connection is gone, nobody is left to read this session's replies.
+                info!("Session {} dropped its reply channel", session_id);
+                self.disconnect(session_id);
+            }
+        }
+    }
+
+    fn reply_session_err(&mut self, session_id: u128, err: SessionError) {
+        self.reply_err(session_id, err.code(), err.to_string());
+    }
+
+    fn reply_room_err(&mut self, session_id: u128, err: RoomError) {
+        self.reply_err(session_id, err.code(), err.to_string());
+    }
+
+    fn reply_user_err(&mut self, session_id: u128, err: UserError) {
+        self.reply_err(session_id, err.code(), err.to_string());
+    }
+
+    /// Something went wrong on our side, log the details and keep them from the client.
+    fn reply_internal(&mut self, session_id: u128, err: Error) {
+        error!("Storage error for session {}: {}", session_id, err);
+        self.reply_err(
+            session_id,
+            ErrorCode::Internal,
+            "Internal server error".to_string(),
+        );
+    }
+
+    fn reply_err(&mut self, session_id: u128, code: ErrorCode, message: String) {
+        let user_id = self
+            .sessions
+            .get(session_id)
+            .and_then(|session| session.state.user_id());
+        self.reply(
+            session_id,
+            ClientReply::Err {
+                code,
+                message: Some(message),
+                user_id,
+                retry_after_ms: None,
+            },
+        );
+    }
+}
+
+/// When a ban or mute lasting `secs` from now ends. Too far out to represent
+/// is as good as forever.
+fn until(secs: u64) -> Option<Time> {
+    let secs = i64::try_from(secs).ok()?;
+    Utc::now().checked_add_signed(TimeDelta::try_seconds(secs)?)
+}
@@ -0,0 +1,362 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+use crate::outbound_queue::{PushResult, QueueSender};
+use crate::rate_limit::{Limiter, RateLimits, TokenBucket};
+use crate::types::{ClientReply, ErrorCode, Presence};
+
+/// Where a session is in its life. Only moves forward, except for leaving a
+/// room which drops it back to `Authenticated`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SessionState {
+    Connected,
+    Authenticated { user_id: u128 },
+    InRoom { user_id: u128, room_id: u128 },
+    Closing,
+}
+
+/// A command that isn't allowed in the session's current state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SessionError {
+    NotLoggedIn,
+    AlreadyLoggedIn,
+    NotInRoom,
+    AlreadyInRoom { room_id: u128 },
+    WrongRoom { room_id: u128 },
+    Closing,
+    InvalidToken,
+}
+
+impl SessionError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            SessionError::NotLoggedIn => ErrorCode::NotLoggedIn,
+            SessionError::AlreadyLoggedIn => ErrorCode::AlreadyLoggedIn,
+            SessionError::NotInRoom | SessionError::WrongRoom { .. } => ErrorCode::NotInRoom,
+            SessionError::AlreadyInRoom { .. } => ErrorCode::AlreadyInRoom,
+            SessionError::Closing => ErrorCode::SessionClosing,
+            SessionError::InvalidToken => ErrorCode::InvalidToken,
+        }
+    }
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::NotLoggedIn => write!(f, "Log in first"),
+            SessionError::AlreadyLoggedIn => write!(f, "Session is already logged in"),
+            SessionError::NotInRoom => write!(f, "Not in a room"),
+            SessionError::AlreadyInRoom { room_id } => {
+                write!(f, "Already in room {}, leave it first", room_id)
+            }
+            SessionError::WrongRoom { room_id } => write!(f, "Not a member of room {}", room_id),
+            SessionError::Closing => write!(f, "Session is closing"),
+            SessionError::InvalidToken => write!(f, "Resume token is unknown or expired"),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+impl SessionState {
+    pub fn user_id(&self) -> Option<u128> {
+        match self {
+            SessionState::Authenticated { user_id } | SessionState::InRoom { user_id, .. } => {
+                Some(*user_id)
+            }
+            SessionState::Connected | SessionState::Closing => None,
+        }
+    }
+
+    pub fn room_id(&self) -> Option<u128> {
+        match self {
+            SessionState::InRoom { room_id, .. } => Some(*room_id),
+            _ => None,
+        }
+    }
+
+    pub fn can_login(&self) -> Result<(), SessionError> {
+        match self {
+            SessionState::Connected => Ok(()),
+            SessionState::Closing => Err(SessionError::Closing),
+            _ => Err(SessionError::AlreadyLoggedIn),
+        }
+    }
+
+    pub fn login(&mut self, user_id: u128) -> Result<(), SessionError> {
+        self.can_login()?;
+        *self = SessionState::Authenticated { user_id };
+        Ok(())
+    }
+
+    pub fn join(&mut self, room_id: u128) -> Result<(), SessionError> {
+        match *self {
+            SessionState::Authenticated { user_id } => {
+                *self = SessionState::InRoom { user_id, room_id };
+                Ok(())
+            }
+            SessionState::InRoom { room_id, .. } => Err(SessionError::AlreadyInRoom { room_id }),
+            SessionState::Connected => Err(SessionError::NotLoggedIn),
+            SessionState::Closing => Err(SessionError::Closing),
+        }
+    }
+
+    /// Returns the room that was left.
+    pub fn leave(&mut self) -> Result<u128, SessionError> {
+        match *self {
+            SessionState::InRoom { user_id, room_id } => {
+                *self = SessionState::Authenticated { user_id };
+                Ok(room_id)
+            }
+            SessionState::Authenticated { .. } => Err(SessionError::NotInRoom),
+            SessionState::Connected => Err(SessionError::NotLoggedIn),
+            SessionState::Closing => Err(SessionError::Closing),
+        }
+    }
+
+    /// Checks that the session is in `room_id` and returns its user.
+    pub fn member_of(&self, room_id: u128) -> Result<u128, SessionError> {
+        match *self {
+            SessionState::InRoom {
+                user_id,
+                room_id: current,
+            } if current == room_id => Ok(user_id),
+            SessionState::InRoom { .. } | SessionState::Authenticated { .. } => {
+                Err(SessionError::WrongRoom { room_id })
+            }
+            SessionState::Connected => Err(SessionError::NotLoggedIn),
+            SessionState::Closing => Err(SessionError::Closing),
+        }
+    }
+
+    pub fn close(&mut self) {
+        *self = SessionState::Closing;
+    }
+}
+
+/// A connected session, logged in or not.
+pub(crate) struct SessionEntry {
+    pub state: SessionState,
+    /// Handed out at login so a dropped connection can pick up where it left off.
+    pub resume_token: Option<String>,
+    /// Set by the client, see `SessionRegistry::presence_of`.
+    pub away: bool,
+    /// Lives for one HTTP API request. Nobody is told about it, and it gets
+    /// nothing meant for the user's other sessions.
+    pub api: bool,
+    /// Where the connection came from, `None` for bots.
+    pub addr: Option<IpAddr>,
+    /// When this session's last typing event was forwarded.
+    pub last_typing: Option<Instant>,
+    pub limiter: Limiter,
+    /// Runs out as requests get turned away for going over `limiter`.
+    pub strikes: TokenBucket,
+    reply_tx: QueueSender,
+    /// Who `by_user` lists this session under, which `state` forgets once it's closing.
+    logged_in_as: Option<u128>,
+}
+
+/// Every connection the router knows about, keyed by session id.
+#[derive(Default)]
+pub(crate) struct SessionRegistry {
+    sessions: HashMap<u128, SessionEntry>,
+    /// Each user's sessions, so presence doesn't have to walk every connection.
+    by_user: HashMap<u128, HashSet<u128>>,
+}
+
+impl SessionRegistry {
+    /// Returns false if the session id is already taken.
+    pub fn register(
+        &mut self,
+        session_id: u128,
+        reply_tx: QueueSender,
+        addr: Option<IpAddr>,
+        limits: &RateLimits,
+    ) -> bool {
+        if self.sessions.contains_key(&session_id) {
+            return false;
+        }
+
+        let now = Instant::now();
+        self.sessions.insert(
+            session_id,
+            SessionEntry {
+                state: SessionState::Connected,
+                resume_token: None,
+                away: false,
+                api: false,
+                addr,
+                last_typing: None,
+                limiter: Limiter::new(limits, now),
+                strikes: TokenBucket::new(limits.strikes, now),
+                reply_tx,
+                logged_in_as: None,
+            },
+        );
+        true
+    }
+
+    /// Logs the session in, fails with `Closing` if it's already gone.
+    pub fn login(
+        &mut self,
+        session_id: u128,
+        user_id: u128,
+    ) -> Result<&mut SessionEntry, SessionError> {
+        let session = self
+            .sessions
+            .get_mut(&session_id)
+            .ok_or(SessionError::Closing)?;
+        session.state.login(user_id)?;
+        session.logged_in_as = Some(user_id);
+        self.by_user.entry(user_id).or_default().insert(session_id);
+        Ok(session)
+    }
+
+    pub fn unregister(&mut self, session_id: u128) -> Option<SessionEntry> {
+        let session = self.sessions.remove(&session_id)?;
+        if let Some(user_id) = session.logged_in_as {
+            if let Some(sessions) = self.by_user.get_mut(&user_id) {
+                sessions.remove(&session_id);
+                if sessions.is_empty() {
+                    self.by_user.remove(&user_id);
+                }
+            }
+        }
+        Some(session)
+    }
+
+    pub fn get(&self, session_id: u128) -> Option<&SessionEntry> {
+        self.sessions.get(&session_id)
+    }
+
+    pub fn get_mut(&mut self, session_id: u128) -> Option<&mut SessionEntry> {
+        self.sessions.get_mut(&session_id)
+    }
+
+    /// Every session `user_id` is logged in on, leaving out API requests.
+    pub fn sessions_of(&self, user_id: u128) -> Vec<u128> {
+        self.logged_in(user_id)
+            .map(|(session_id, _)| session_id)
+            .collect()
+    }
+
+    /// Online while any of the user's sessions isn't away, offline once none are left.
+    pub fn presence_of(&self, user_id: u128) -> Presence {
+        let mut sessions = self
+            .logged_in(user_id)
+            .map(|(_, session)| session)
+            .peekable();
+        if sessions.peek().is_none() {
+            Presence::Offline
+        } else if sessions.any(|session| !session.away) {
+            Presence::Online
+        } else {
+            Presence::Away
+        }
+    }
+
+    /// The user's sessions that aren't closing or API requests.
+    fn logged_in(&self, user_id: u128) -> impl Iterator<Item = (u128, &SessionEntry)> {
+        self.by_user
+            .get(&user_id)
+            .into_iter()
+            .flatten()
+            .filter_map(move |&session_id| {
+                let session = self.sessions.get(&session_id)?;
+                (session.state.user_id() == Some(user_id) && !session.api)
+                    .then_some((session_id, session))
+            })
+    }
+
+    /// Queues a reply without ever waiting on the connection.
+    pub fn send(&self, session_id: u128, reply: ClientReply) -> PushResult {
+        match self.sessions.get(&session_id) {
+            Some(session) => session.reply_tx.push(reply),
+            None => PushResult::Closed,
+        }
+    }
+}
+
+enum TokenState {
+    /// The session that owns the token is still connected.
+    Live { session_id: u128 },
+    /// The connection dropped, the token can be redeemed until `expires`.
+    Detached {
+        room_id: Option<u128>,
+        expires: Instant,
+    },
+}
+
+struct ResumeEntry {
+    user_id: u128,
+    state: TokenState,
+}
+
+/// What a redeemed token gives back.
+pub(crate) struct Resumed {
+    pub user_id: u128,
+    /// Set if the old connection never told us it went away.
+    pub live_session: Option<u128>,
+    /// The room the old session was in when it dropped.
+    pub room_id: Option<u128>,
+}
+
+/// Resume tokens, each one good for a single reconnect.
+#[derive(Default)]
+pub(crate) struct ResumeTokens {
+    tokens: HashMap<String, ResumeEntry>,
+}
+
+impl ResumeTokens {
+    pub fn issue(&mut self, user_id: u128, session_id: u128) -> String {
+        let token = Uuid::new_v4().simple().to_string();
+        self.tokens.insert(
+            token.clone(),
+            ResumeEntry {
+                user_id,
+                state: TokenState::Live { session_id },
+            },
+        );
+        token
+    }
+
+    /// The session behind `token` went away, keep it around for `ttl`.
+    pub fn detach(&mut self, token: &str, room_id: Option<u128>, ttl: Duration) {
+        let now = Instant::now();
+        self.tokens.retain(|_, entry| match entry.state {
+            TokenState::Detached { expires, .. } => expires > now,
+            TokenState::Live { .. } => true,
+        });
+
+        if let Some(entry) = self.tokens.get_mut(token) {
+            entry.state = TokenState::Detached {
+                room_id,
+                expires: now + ttl,
+            };
+        }
+    }
+
+    /// Uses up the token. `None` if it is unknown or expired.
+    pub fn redeem(&mut self, token: &str) -> Option<Resumed> {
+        let entry = self.tokens.remove(token)?;
+        match entry.state {
+            TokenState::Live { session_id } => Some(Resumed {
+                user_id: entry.user_id,
+                live_session: Some(session_id),
+                room_id: None,
+            }),
+            TokenState::Detached { room_id, expires } if expires > Instant::now() => {
+                Some(Resumed {
+                    user_id: entry.user_id,
+                    live_session: None,
+                    room_id,
+                })
+            }
+            TokenState::Detached { .. } => None,
+        }
+    }
+}
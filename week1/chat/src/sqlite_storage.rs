@@ -0,0 +1,770 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use log::info;
+use rusqlite::types::Type;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Row};
+
+use crate::storage::{Storage, StoredBan, StoredMute, StoredRoom};
+use crate::types::{
+    Cursor, DirectMessage, Error, Pair, Revision, Seq, ThreadSummary, Time, UserTextMessage,
+};
+use crate::user_manager::User;
+
+/// Applied in order on startup, `PRAGMA user_version` remembers how many ran.
+/// Only ever append to this list, never edit an entry that has shipped.
+const MIGRATIONS: &[&str] = &[
+    // 1: users, rooms, memberships and room history
+    "CREATE TABLE users (
+        id TEXT PRIMARY KEY,
+        username TEXT NOT NULL UNIQUE,
+        display_name TEXT NOT NULL,
+        password_hash TEXT NOT NULL
+    );
+    CREATE TABLE rooms (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE,
+        capacity INTEGER NOT NULL
+    );
+    CREATE TABLE memberships (
+        room_id TEXT NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+        user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        PRIMARY KEY (room_id, user_id)
+    );
+    CREATE TABLE messages (
+        id TEXT PRIMARY KEY,
+        room_id TEXT NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+        seq INTEGER NOT NULL,
+        time TEXT NOT NULL,
+        text TEXT NOT NULL,
+        from_user TEXT NOT NULL,
+        username TEXT NOT NULL,
+        UNIQUE (room_id, seq)
+    );",
+    // 2: direct messages, user_a is always the smaller id of the pair
+    "CREATE TABLE direct_messages (
+        id TEXT PRIMARY KEY,
+        user_a TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        user_b TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        seq INTEGER NOT NULL,
+        time TEXT NOT NULL,
+        text TEXT NOT NULL,
+        from_user TEXT NOT NULL,
+        to_user TEXT NOT NULL,
+        username TEXT NOT NULL,
+        delivered INTEGER NOT NULL,
+        UNIQUE (user_a, user_b, seq)
+    );
+    CREATE INDEX direct_pending ON direct_messages (to_user) WHERE delivered = 0;",
+    // 3: last seen, NULL until the user first disconnects
+    "ALTER TABLE users ADD COLUMN last_seen TEXT;",
+    // 4: read markers
+    "ALTER TABLE memberships ADD COLUMN last_read INTEGER NOT NULL DEFAULT 0;",
+    // 5: room owners, edits, tombstones and revisions
+    "ALTER TABLE rooms ADD COLUMN owner TEXT;
+    ALTER TABLE messages ADD COLUMN edited TEXT;
+    ALTER TABLE messages ADD COLUMN deleted INTEGER NOT NULL DEFAULT 0;
+    CREATE TABLE revisions (
+        message_id TEXT NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+        text TEXT NOT NULL,
+        time TEXT NOT NULL
+    );
+    CREATE INDEX revisions_message ON revisions (message_id);",
+    // 6: reactions
+    "CREATE TABLE reactions (
+        message_id TEXT NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+        user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        emoji TEXT NOT NULL,
+        PRIMARY KEY (message_id, user_id, emoji)
+    );",
+    // 7: threads
+    "ALTER TABLE messages ADD COLUMN parent_id TEXT;
+    CREATE INDEX messages_parent ON messages (parent_id);",
+    // 8: moderators and bans, a ban with no end time lasts until lifted
+    "CREATE TABLE moderators (
+        room_id TEXT NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+        user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        PRIMARY KEY (room_id, user_id)
+    );
+    CREATE TABLE bans (
+        room_id TEXT NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+        user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        banned_by TEXT NOT NULL,
+        until TEXT,
+        reason TEXT,
+        PRIMARY KEY (room_id, user_id)
+    );",
+    // 9: bot accounts
+    "ALTER TABLE users ADD COLUMN bot INTEGER NOT NULL DEFAULT 0;",
+    // 10: mutes, kept the same way as bans
+    "CREATE TABLE mutes (
+        room_id TEXT NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+        user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        muted_by TEXT NOT NULL,
+        until TEXT,
+        PRIMARY KEY (room_id, user_id)
+    );",
+];
+
+/// File backed storage. Ids are stored as decimal text since SQLite has no
+/// 128 bit integers.
+pub(crate) struct SqliteStorage {
+    conn: Connection,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let mut conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        migrate(&mut conn)?;
+        Ok(SqliteStorage { conn })
+    }
+}
+
+fn migrate(conn: &mut Connection) -> Result<(), Error> {
+    let applied: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+    for (version, sql) in MIGRATIONS.iter().enumerate().skip(applied) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(sql)?;
+        tx.pragma_update(None, "user_version", version + 1)?;
+        tx.commit()?;
+        info!("Applied database migration {}", version + 1);
+    }
+    Ok(())
+}
+
+fn get_id(row: &Row, idx: usize) -> rusqlite::Result<u128> {
+    let text: String = row.get(idx)?;
+    text.parse()
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(idx, Type::Text, Box::new(e)))
+}
+
+fn get_opt_id(row: &Row, idx: usize) -> rusqlite::Result<Option<u128>> {
+    let text: Option<String> = row.get(idx)?;
+    text.map(|text| {
+        text.parse()
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(idx, Type::Text, Box::new(e)))
+    })
+    .transpose()
+}
+
+const MESSAGE_COLUMNS: &str =
+    "id, room_id, seq, time, text, from_user, username, edited, deleted, parent_id";
+
+fn message_from_row(row: &Row) -> rusqlite::Result<UserTextMessage> {
+    let message = UserTextMessage::new(
+        get_id(row, 0)?,
+        get_id(row, 1)?,
+        row.get(2)?,
+        row.get(3)?,
+        row.get(4)?,
+        get_id(row, 5)?,
+        row.get(6)?,
+    );
+    Ok(message
+        .with_state(row.get(7)?, row.get(8)?)
+        .with_parent(get_opt_id(row, 9)?))
+}
+
+const DIRECT_COLUMNS: &str = "id, seq, time, text, from_user, to_user, username";
+
+/// Most ids bound in one `IN (...)`, well under the 999 older SQLite builds allow.
+const MAX_PARAMS: usize = 500;
+
+fn direct_from_row(row: &Row) -> rusqlite::Result<DirectMessage> {
+    Ok(DirectMessage::new(
+        get_id(row, 0)?,
+        row.get(1)?,
+        row.get(2)?,
+        row.get(3)?,
+        get_id(row, 4)?,
+        get_id(row, 5)?,
+        row.get(6)?,
+    ))
+}
+
+impl SqliteStorage {
+    /// A page of the room, or of one thread in it when `root_id` is set.
+    fn message_page(
+        &self,
+        room_id: u128,
+        root_id: Option<u128>,
+        cursor: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Vec<Arc<UserTextMessage>>, Error> {
+        // Newest first when reading backwards so LIMIT keeps the right end,
+        // flipped back to oldest first below.
+        let (filter, order, seq) = match cursor {
+            Some(Cursor::After(seq)) => ("AND seq > ?2", "ASC", seq),
+            Some(Cursor::Before(seq)) => ("AND seq < ?2", "DESC", seq),
+            // Sequence numbers start at 1, so this matches everything.
+            None => ("AND seq > ?2", "DESC", 0),
+        };
+        let sql = format!(
+            "SELECT {} FROM messages
+             WHERE room_id = ?1 {} AND (?4 IS NULL OR parent_id = ?4)
+             ORDER BY seq {} LIMIT ?3",
+            MESSAGE_COLUMNS, filter, order
+        );
+
+        let mut stmt = self.conn.prepare_cached(&sql)?;
+        let root_id = root_id.map(|id| id.to_string());
+        let mut messages: Vec<_> = stmt
+            .query_map(params![room_id.to_string(), seq, limit, root_id], |row| {
+                message_from_row(row).map(Arc::new)
+            })?
+            .collect::<Result<_, _>>()?;
+        if order == "DESC" {
+            messages.reverse();
+        }
+        Ok(messages)
+    }
+
+    /// Runs `sql` once per chunk of `ids`, its `{}` filled with that many
+    /// placeholders, and hands every row to `f`. Pages can be as big as
+    /// `CHAT_MAX_HISTORY_PAGE` says, so one `IN (...)` may not fit them.
+    fn for_each_in(
+        &self,
+        sql: &str,
+        ids: &[u128],
+        mut f: impl FnMut(&Row) -> rusqlite::Result<()>,
+    ) -> Result<(), Error> {
+        for chunk in ids.chunks(MAX_PARAMS) {
+            let placeholders = vec!["?"; chunk.len()].join(", ");
+            let mut stmt = self.conn.prepare(&sql.replace("{}", &placeholders))?;
+            let mut rows = stmt.query(params_from_iter(chunk.iter().map(u128::to_string)))?;
+            while let Some(row) = rows.next()? {
+                f(row)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn users(&self) -> Result<Vec<User>, Error> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT id, username, display_name, password_hash, bot FROM users")?;
+        let users = stmt
+            .query_map([], |row| {
+                let mut user = User::new(get_id(row, 0)?, row.get(1)?, row.get(2)?, row.get(3)?);
+                user.bot = row.get(4)?;
+                Ok(user)
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(users)
+    }
+
+    fn insert_user(&mut self, user: &User) -> Result<(), Error> {
+        self.conn.execute(
+            "INSERT INTO users (id, username, display_name, password_hash, bot)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                user.id.to_string(),
+                user.username,
+                user.display_name,
+                user.password_hash(),
+                user.bot
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn set_display_name(&mut self, user_id: u128, display_name: &str) -> Result<(), Error> {
+        self.conn.execute(
+            "UPDATE users SET display_name = ?2 WHERE id = ?1",
+            params![user_id.to_string(), display_name],
+        )?;
+        Ok(())
+    }
+
+    fn last_seen(&self) -> Result<Vec<(u128, Time)>, Error> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT id, last_seen FROM users WHERE last_seen IS NOT NULL")?;
+        let last_seen = stmt
+            .query_map([], |row| Ok((get_id(row, 0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        Ok(last_seen)
+    }
+
+    fn set_last_seen(&mut self, user_id: u128, time: Time) -> Result<(), Error> {
+        self.conn.execute(
+            "UPDATE users SET last_seen = ?2 WHERE id = ?1",
+            params![user_id.to_string(), time],
+        )?;
+        Ok(())
+    }
+
+    fn rooms(&self) -> Result<Vec<StoredRoom>, Error> {
+        let mut stmt = self.conn.prepare(
+            "SELECT r.id, r.name, r.capacity, COALESCE(MAX(m.seq), 0), r.owner
+             FROM rooms r LEFT JOIN messages m ON m.room_id = r.id
+             GROUP BY r.id",
+        )?;
+        let rooms = stmt
+            .query_map([], |row| {
+                Ok(StoredRoom {
+                    id: get_id(row, 0)?,
+                    name: row.get(1)?,
+                    capacity: row.get(2)?,
+                    last_seq: row.get(3)?,
+                    owner: get_opt_id(row, 4)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(rooms)
+    }
+
+    fn insert_room(&mut self, room: &StoredRoom) -> Result<(), Error> {
+        self.conn.execute(
+            "INSERT INTO rooms (id, name, capacity, owner) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                room.id.to_string(),
+                room.name,
+                room.capacity,
+                room.owner.map(|owner| owner.to_string())
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn rename_room(&mut self, room_id: u128, name: &str) -> Result<(), Error> {
+        self.conn.execute(
+            "UPDATE rooms SET name = ?2 WHERE id = ?1",
+            params![room_id.to_string(), name],
+        )?;
+        Ok(())
+    }
+
+    fn delete_room(&mut self, room_id: u128) -> Result<(), Error> {
+        self.conn
+            .execute("DELETE FROM rooms WHERE id = ?1", [room_id.to_string()])?;
+        Ok(())
+    }
+
+    fn add_member(&mut self, room_id: u128, user_id: u128, last_read: Seq) -> Result<(), Error> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO memberships (room_id, user_id, last_read) VALUES (?1, ?2, ?3)",
+            params![room_id.to_string(), user_id.to_string(), last_read],
+        )?;
+        Ok(())
+    }
+
+    fn moderators(&self) -> Result<Vec<(u128, u128)>, Error> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT room_id, user_id FROM moderators")?;
+        let moderators = stmt
+            .query_map([], |row| Ok((get_id(row, 0)?, get_id(row, 1)?)))?
+            .collect::<Result<_, _>>()?;
+        Ok(moderators)
+    }
+
+    fn set_moderator(
+        &mut self,
+        room_id: u128,
+        user_id: u128,
+        moderator: bool,
+    ) -> Result<(), Error> {
+        let sql = if moderator {
+            "INSERT OR IGNORE INTO moderators (room_id, user_id) VALUES (?1, ?2)"
+        } else {
+            "DELETE FROM moderators WHERE room_id = ?1 AND user_id = ?2"
+        };
+        self.conn
+            .execute(sql, params![room_id.to_string(), user_id.to_string()])?;
+        Ok(())
+    }
+
+    fn ban(&mut self, ban: &StoredBan) -> Result<(), Error> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO bans (room_id, user_id, banned_by, until, reason)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                ban.room_id.to_string(),
+                ban.user_id.to_string(),
+                ban.by.to_string(),
+                ban.until,
+                ban.reason
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn unban(&mut self, room_id: u128, user_id: u128) -> Result<(), Error> {
+        self.conn.execute(
+            "DELETE FROM bans WHERE room_id = ?1 AND user_id = ?2",
+            params![room_id.to_string(), user_id.to_string()],
+        )?;
+        Ok(())
+    }
+
+    fn bans(&self) -> Result<Vec<StoredBan>, Error> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT room_id, user_id, banned_by, until, reason FROM bans")?;
+        let bans = stmt
+            .query_map([], |row| {
+                Ok(StoredBan {
+                    room_id: get_id(row, 0)?,
+                    user_id: get_id(row, 1)?,
+                    by: get_id(row, 2)?,
+                    until: row.get(3)?,
+                    reason: row.get(4)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(bans)
+    }
+
+    fn mute(&mut self, mute: &StoredMute) -> Result<(), Error> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO mutes (room_id, user_id, muted_by, until)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                mute.room_id.to_string(),
+                mute.user_id.to_string(),
+                mute.by.to_string(),
+                mute.until
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn unmute(&mut self, room_id: u128, user_id: u128) -> Result<(), Error> {
+        self.conn.execute(
+            "DELETE FROM mutes WHERE room_id = ?1 AND user_id = ?2",
+            params![room_id.to_string(), user_id.to_string()],
+        )?;
+        Ok(())
+    }
+
+    fn mutes(&self) -> Result<Vec<StoredMute>, Error> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT room_id, user_id, muted_by, until FROM mutes")?;
+        let mutes = stmt
+            .query_map([], |row| {
+                Ok(StoredMute {
+                    room_id: get_id(row, 0)?,
+                    user_id: get_id(row, 1)?,
+                    by: get_id(row, 2)?,
+                    until: row.get(3)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(mutes)
+    }
+
+    fn remove_member(&mut self, room_id: u128, user_id: u128) -> Result<(), Error> {
+        self.conn.execute(
+            "DELETE FROM memberships WHERE room_id = ?1 AND user_id = ?2",
+            params![room_id.to_string(), user_id.to_string()],
+        )?;
+        Ok(())
+    }
+
+    fn members(&self, room_id: u128) -> Result<Vec<u128>, Error> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT user_id FROM memberships WHERE room_id = ?1")?;
+        let members = stmt
+            .query_map([room_id.to_string()], |row| get_id(row, 0))?
+            .collect::<Result<_, _>>()?;
+        Ok(members)
+    }
+
+    fn memberships(&self) -> Result<Vec<(u128, u128, Seq)>, Error> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT room_id, user_id, last_read FROM memberships")?;
+        let memberships = stmt
+            .query_map([], |row| {
+                Ok((get_id(row, 0)?, get_id(row, 1)?, row.get(2)?))
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(memberships)
+    }
+
+    fn set_last_read(&mut self, room_id: u128, user_id: u128, last_read: Seq) -> Result<(), Error> {
+        self.conn.execute(
+            "UPDATE memberships SET last_read = ?3 WHERE room_id = ?1 AND user_id = ?2",
+            params![room_id.to_string(), user_id.to_string(), last_read],
+        )?;
+        Ok(())
+    }
+
+    fn append_message(&mut self, message: &UserTextMessage) -> Result<(), Error> {
+        self.conn.execute(
+            "INSERT INTO messages (id, room_id, seq, time, text, from_user, username, parent_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                message.id().to_string(),
+                message.room_id().to_string(),
+                message.seq(),
+                message.time(),
+                message.text(),
+                message.from().to_string(),
+                message.username(),
+                message.parent_id().map(|id| id.to_string()),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn messages(
+        &self,
+        room_id: u128,
+        cursor: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Vec<Arc<UserTextMessage>>, Error> {
+        self.message_page(room_id, None, cursor, limit)
+    }
+
+    fn message(
+        &self,
+        room_id: u128,
+        message_id: u128,
+    ) -> Result<Option<Arc<UserTextMessage>>, Error> {
+        let sql = format!(
+            "SELECT {} FROM messages WHERE room_id = ?1 AND id = ?2",
+            MESSAGE_COLUMNS
+        );
+        let message = self
+            .conn
+            .prepare_cached(&sql)?
+            .query_row(
+                params![room_id.to_string(), message_id.to_string()],
+                message_from_row,
+            )
+            .optional()?;
+        Ok(message.map(Arc::new))
+    }
+
+    fn edit_message(
+        &mut self,
+        message: &UserTextMessage,
+        revision: &Revision,
+    ) -> Result<(), Error> {
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO revisions (message_id, text, time) VALUES (?1, ?2, ?3)",
+            params![message.id().to_string(), revision.text, revision.time],
+        )?;
+        tx.execute(
+            "UPDATE messages SET text = ?2, edited = ?3 WHERE id = ?1",
+            params![message.id().to_string(), message.text(), message.edited()],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn delete_message(&mut self, message: &UserTextMessage) -> Result<(), Error> {
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "DELETE FROM revisions WHERE message_id = ?1",
+            [message.id().to_string()],
+        )?;
+        tx.execute(
+            "DELETE FROM reactions WHERE message_id = ?1",
+            [message.id().to_string()],
+        )?;
+        tx.execute(
+            "UPDATE messages SET text = ?2, deleted = ?3 WHERE id = ?1",
+            params![message.id().to_string(), message.text(), message.deleted()],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn revisions(&self, message_id: u128) -> Result<Vec<Revision>, Error> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT text, time FROM revisions WHERE message_id = ?1 ORDER BY rowid",
+        )?;
+        let revisions = stmt
+            .query_map([message_id.to_string()], |row| {
+                Ok(Revision {
+                    text: row.get(0)?,
+                    time: row.get(1)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(revisions)
+    }
+
+    fn thread_messages(
+        &self,
+        room_id: u128,
+        root_id: u128,
+        cursor: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Vec<Arc<UserTextMessage>>, Error> {
+        self.message_page(room_id, Some(root_id), cursor, limit)
+    }
+
+    fn thread_summaries(&self, root_ids: &[u128]) -> Result<HashMap<u128, ThreadSummary>, Error> {
+        let mut threads = HashMap::new();
+        self.for_each_in(
+            "SELECT parent_id, COUNT(*), MAX(time) FROM messages
+             WHERE parent_id IN ({}) AND deleted = 0 GROUP BY parent_id",
+            root_ids,
+            |row| {
+                threads.insert(
+                    get_id(row, 0)?,
+                    ThreadSummary {
+                        reply_count: row.get(1)?,
+                        last_reply: row.get(2)?,
+                    },
+                );
+                Ok(())
+            },
+        )?;
+        Ok(threads)
+    }
+
+    fn thread_participants(&self, room_id: u128, root_id: u128) -> Result<Vec<u128>, Error> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT DISTINCT from_user FROM messages
+             WHERE room_id = ?1 AND (id = ?2 OR parent_id = ?2)",
+        )?;
+        let users = stmt
+            .query_map(params![room_id.to_string(), root_id.to_string()], |row| {
+                get_id(row, 0)
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(users)
+    }
+
+    fn add_reaction(
+        &mut self,
+        message_id: u128,
+        user_id: u128,
+        emoji: &str,
+    ) -> Result<bool, Error> {
+        let added = self.conn.execute(
+            "INSERT OR IGNORE INTO reactions (message_id, user_id, emoji) VALUES (?1, ?2, ?3)",
+            params![message_id.to_string(), user_id.to_string(), emoji],
+        )?;
+        Ok(added > 0)
+    }
+
+    fn remove_reaction(
+        &mut self,
+        message_id: u128,
+        user_id: u128,
+        emoji: &str,
+    ) -> Result<bool, Error> {
+        let removed = self.conn.execute(
+            "DELETE FROM reactions WHERE message_id = ?1 AND user_id = ?2 AND emoji = ?3",
+            params![message_id.to_string(), user_id.to_string(), emoji],
+        )?;
+        Ok(removed > 0)
+    }
+
+    fn reactions(&self, message_ids: &[u128]) -> Result<HashMap<u128, Vec<(u128, String)>>, Error> {
+        let mut reactions: HashMap<u128, Vec<(u128, String)>> = HashMap::new();
+        self.for_each_in(
+            "SELECT message_id, user_id, emoji FROM reactions
+             WHERE message_id IN ({}) ORDER BY rowid",
+            message_ids,
+            |row| {
+                reactions
+                    .entry(get_id(row, 0)?)
+                    .or_default()
+                    .push((get_id(row, 1)?, row.get(2)?));
+                Ok(())
+            },
+        )?;
+        Ok(reactions)
+    }
+
+    fn conversations(&self) -> Result<Vec<(Pair, Seq)>, Error> {
+        let mut stmt = self.conn.prepare(
+            "SELECT user_a, user_b, MAX(seq) FROM direct_messages GROUP BY user_a, user_b",
+        )?;
+        let conversations = stmt
+            .query_map([], |row| {
+                Ok((Pair(get_id(row, 0)?, get_id(row, 1)?), row.get(2)?))
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(conversations)
+    }
+
+    fn append_direct(&mut self, message: &DirectMessage, delivered: bool) -> Result<(), Error> {
+        let pair = message.pair();
+        self.conn.execute(
+            "INSERT INTO direct_messages
+             (id, user_a, user_b, seq, time, text, from_user, to_user, username, delivered)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                message.id().to_string(),
+                pair.0.to_string(),
+                pair.1.to_string(),
+                message.seq(),
+                message.time(),
+                message.text(),
+                message.from().to_string(),
+                message.to().to_string(),
+                message.username(),
+                delivered,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn direct_messages(
+        &self,
+        pair: Pair,
+        cursor: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Vec<Arc<DirectMessage>>, Error> {
+        // Same trick as `messages`.
+        let (filter, order, seq) = match cursor {
+            Some(Cursor::After(seq)) => ("AND seq > ?3", "ASC", seq),
+            Some(Cursor::Before(seq)) => ("AND seq < ?3", "DESC", seq),
+            None => ("AND seq > ?3", "DESC", 0),
+        };
+        let sql = format!(
+            "SELECT {} FROM direct_messages
+             WHERE user_a = ?1 AND user_b = ?2 {} ORDER BY seq {} LIMIT ?4",
+            DIRECT_COLUMNS, filter, order
+        );
+
+        let mut stmt = self.conn.prepare_cached(&sql)?;
+        let mut messages: Vec<_> = stmt
+            .query_map(
+                params![pair.0.to_string(), pair.1.to_string(), seq, limit],
+                |row| direct_from_row(row).map(Arc::new),
+            )?
+            .collect::<Result<_, _>>()?;
+        if order == "DESC" {
+            messages.reverse();
+        }
+        Ok(messages)
+    }
+
+    fn pending_direct(&self, user_id: u128) -> Result<Vec<(u128, Seq)>, Error> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT from_user, MIN(seq) FROM direct_messages
+             WHERE to_user = ?1 AND delivered = 0 GROUP BY from_user",
+        )?;
+        let pending = stmt
+            .query_map([user_id.to_string()], |row| {
+                Ok((get_id(row, 0)?, row.get(1)?))
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(pending)
+    }
+
+    fn mark_direct_delivered(&mut self, user_id: u128) -> Result<(), Error> {
+        self.conn.execute(
+            "UPDATE direct_messages SET delivered = 1 WHERE to_user = ?1 AND delivered = 0",
+            [user_id.to_string()],
+        )?;
+        Ok(())
+    }
+}
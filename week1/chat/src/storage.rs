@@ -0,0 +1,780 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::reactions;
+use crate::sqlite_storage::SqliteStorage;
+use crate::types::{
+    Cursor, DirectMessage, DirectMessages, Error, Messages, Pair, Revision, Seq, ThreadSummary,
+    Time, UserTextMessage,
+};
+use crate::user_manager::User;
+
+/// Path that selects `MemoryStorage` instead of a database file.
+pub(crate) const IN_MEMORY: &str = ":memory:";
+
+/// A room as it is persisted, without any of its live members.
+#[derive(Debug, Clone)]
+pub(crate) struct StoredRoom {
+    pub id: u128,
+    pub name: String,
+    pub capacity: u32,
+    /// Sequence number of the room's newest message.
+    pub last_seq: Seq,
+    /// Whoever created the room. Rooms from before owners were tracked have none.
+    pub owner: Option<u128>,
+}
+
+/// Someone kept out of a room.
+#[derive(Debug, Clone)]
+pub(crate) struct StoredBan {
+    pub room_id: u128,
+    pub user_id: u128,
+    pub by: u128,
+    /// `None` until someone lifts the ban.
+    pub until: Option<Time>,
+    pub reason: Option<String>,
+}
+
+/// Someone kept from talking in a room.
+#[derive(Debug, Clone)]
+pub(crate) struct StoredMute {
+    pub room_id: u128,
+    pub user_id: u128,
+    pub by: u128,
+    /// `None` until someone lifts the mute.
+    pub until: Option<Time>,
+}
+
+/// Everything the router needs to survive a restart. The router writes
+/// through to it before changing its own state, and reads it back on startup.
+pub(crate) trait Storage: Send {
+    fn users(&self) -> Result<Vec<User>, Error>;
+    fn insert_user(&mut self, user: &User) -> Result<(), Error>;
+    fn set_display_name(&mut self, user_id: u128, display_name: &str) -> Result<(), Error>;
+    /// When each user that has ever logged out was last connected.
+    fn last_seen(&self) -> Result<Vec<(u128, Time)>, Error>;
+    fn set_last_seen(&mut self, user_id: u128, time: Time) -> Result<(), Error>;
+
+    fn rooms(&self) -> Result<Vec<StoredRoom>, Error>;
+    fn insert_room(&mut self, room: &StoredRoom) -> Result<(), Error>;
+    fn rename_room(&mut self, room_id: u128, name: &str) -> Result<(), Error>;
+    /// Removes the room along with its memberships and history.
+    fn delete_room(&mut self, room_id: u128) -> Result<(), Error>;
+
+    /// Does nothing if the user is already a member.
+    fn add_member(&mut self, room_id: u128, user_id: u128, last_read: Seq) -> Result<(), Error>;
+    fn remove_member(&mut self, room_id: u128, user_id: u128) -> Result<(), Error>;
+    fn members(&self, room_id: u128) -> Result<Vec<u128>, Error>;
+    /// Every membership as `(room_id, user_id, last_read)`.
+    fn memberships(&self) -> Result<Vec<(u128, u128, Seq)>, Error>;
+    fn set_last_read(&mut self, room_id: u128, user_id: u128, last_read: Seq) -> Result<(), Error>;
+    /// Every moderator as `(room_id, user_id)`. Owners are on the room itself.
+    fn moderators(&self) -> Result<Vec<(u128, u128)>, Error>;
+    fn set_moderator(&mut self, room_id: u128, user_id: u128, moderator: bool)
+        -> Result<(), Error>;
+    /// Replaces any earlier ban of the same user from the same room.
+    fn ban(&mut self, ban: &StoredBan) -> Result<(), Error>;
+    fn unban(&mut self, room_id: u128, user_id: u128) -> Result<(), Error>;
+    fn bans(&self) -> Result<Vec<StoredBan>, Error>;
+    /// Replaces any earlier mute of the same user in the same room.
+    fn mute(&mut self, mute: &StoredMute) -> Result<(), Error>;
+    fn unmute(&mut self, room_id: u128, user_id: u128) -> Result<(), Error>;
+    fn mutes(&self) -> Result<Vec<StoredMute>, Error>;
+
+    fn append_message(&mut self, message: &UserTextMessage) -> Result<(), Error>;
+    /// Up to `limit` messages next to the cursor, oldest first. Without a
+    /// cursor this is the newest messages in the room.
+    fn messages(
+        &self,
+        room_id: u128,
+        cursor: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Vec<Arc<UserTextMessage>>, Error>;
+    fn message(
+        &self,
+        room_id: u128,
+        message_id: u128,
+    ) -> Result<Option<Arc<UserTextMessage>>, Error>;
+    /// Overwrites the message and keeps `revision`, the text it had before.
+    fn edit_message(&mut self, message: &UserTextMessage, revision: &Revision)
+        -> Result<(), Error>;
+    /// Overwrites the message with its tombstone and forgets its revisions,
+    /// so nothing of a deleted message is left to leak.
+    fn delete_message(&mut self, message: &UserTextMessage) -> Result<(), Error>;
+    /// Oldest first.
+    fn revisions(&self, message_id: u128) -> Result<Vec<Revision>, Error>;
+
+    /// Up to `limit` replies to `root_id` next to the cursor, oldest first,
+    /// the same way `messages` pages a room.
+    fn thread_messages(
+        &self,
+        room_id: u128,
+        root_id: u128,
+        cursor: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Vec<Arc<UserTextMessage>>, Error>;
+    /// Reply counts for the given roots that have any replies left.
+    fn thread_summaries(&self, root_ids: &[u128]) -> Result<HashMap<u128, ThreadSummary>, Error>;
+    /// Everyone that wrote the root or a reply in the thread.
+    fn thread_participants(&self, room_id: u128, root_id: u128) -> Result<Vec<u128>, Error>;
+
+    /// Returns false if the user had already reacted with that emoji.
+    fn add_reaction(&mut self, message_id: u128, user_id: u128, emoji: &str)
+        -> Result<bool, Error>;
+    /// Returns false if there was no such reaction.
+    fn remove_reaction(
+        &mut self,
+        message_id: u128,
+        user_id: u128,
+        emoji: &str,
+    ) -> Result<bool, Error>;
+    /// `(user_id, emoji)` per message that has any, oldest reaction first.
+    fn reactions(&self, message_ids: &[u128]) -> Result<HashMap<u128, Vec<(u128, String)>>, Error>;
+
+    /// Every conversation with the sequence number of its newest message.
+    fn conversations(&self) -> Result<Vec<(Pair, Seq)>, Error>;
+    /// `delivered` is false when the recipient had no session open to take it.
+    fn append_direct(&mut self, message: &DirectMessage, delivered: bool) -> Result<(), Error>;
+    /// Same paging as `messages`, for the conversation between a pair of users.
+    fn direct_messages(
+        &self,
+        pair: Pair,
+        cursor: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Vec<Arc<DirectMessage>>, Error>;
+    /// Everyone with direct messages still waiting for `user_id` to log in,
+    /// each with the sequence number of the oldest one waiting.
+    fn pending_direct(&self, user_id: u128) -> Result<Vec<(u128, Seq)>, Error>;
+    fn mark_direct_delivered(&mut self, user_id: u128) -> Result<(), Error>;
+}
+
+/// Reads one page of history and works out the cursor for the page after it.
+pub(crate) fn history_page(
+    storage: &dyn Storage,
+    room_id: u128,
+    cursor: Option<Cursor>,
+    limit: usize,
+) -> Result<(Vec<Arc<UserTextMessage>>, Option<Cursor>), Error> {
+    // One extra tells us whether there is another page without a COUNT.
+    let messages = storage.messages(room_id, cursor, limit + 1)?;
+    let (messages, next) = paginate(messages, cursor, limit, |message| message.seq());
+    Ok((annotate(storage, messages)?, next))
+}
+
+/// `history_page` for the replies to one thread.
+pub(crate) fn thread_page(
+    storage: &dyn Storage,
+    room_id: u128,
+    root_id: u128,
+    cursor: Option<Cursor>,
+    limit: usize,
+) -> Result<(Vec<Arc<UserTextMessage>>, Option<Cursor>), Error> {
+    let messages = storage.thread_messages(room_id, root_id, cursor, limit + 1)?;
+    let (messages, next) = paginate(messages, cursor, limit, |message| message.seq());
+    Ok((annotate(storage, messages)?, next))
+}
+
+/// Copies reaction and thread summaries onto the messages that have any.
+pub(crate) fn annotate(
+    storage: &dyn Storage,
+    messages: Vec<Arc<UserTextMessage>>,
+) -> Result<Vec<Arc<UserTextMessage>>, Error> {
+    let ids: Vec<u128> = messages.iter().map(|message| message.id()).collect();
+    let reactions = storage.reactions(&ids)?;
+    let threads = storage.thread_summaries(&ids)?;
+    if reactions.is_empty() && threads.is_empty() {
+        return Ok(messages);
+    }
+
+    Ok(messages
+        .into_iter()
+        .map(|message| {
+            let reactions = reactions.get(&message.id());
+            let thread = threads.get(&message.id()).copied();
+            if reactions.is_none() && thread.is_none() {
+                return message;
+            }
+            let reactions = reactions
+                .map(|reactions| reactions::summarize(reactions))
+                .unwrap_or_default();
+            Arc::new(message.annotated(reactions, thread))
+        })
+        .collect())
+}
+
+/// `history_page` for a conversation between two users.
+pub(crate) fn direct_page(
+    storage: &dyn Storage,
+    pair: Pair,
+    cursor: Option<Cursor>,
+    limit: usize,
+) -> Result<(Vec<Arc<DirectMessage>>, Option<Cursor>), Error> {
+    let messages = storage.direct_messages(pair, cursor, limit + 1)?;
+    Ok(paginate(messages, cursor, limit, |message| message.seq()))
+}
+
+/// Trims a `limit + 1` read down to `limit` and turns the extra into a cursor.
+fn paginate<T>(
+    mut messages: Vec<Arc<T>>,
+    cursor: Option<Cursor>,
+    limit: usize,
+    seq: impl Fn(&T) -> Seq,
+) -> (Vec<Arc<T>>, Option<Cursor>) {
+    if messages.len() <= limit {
+        return (messages, None);
+    }
+
+    let next = match cursor {
+        Some(Cursor::After(_)) => {
+            messages.truncate(limit);
+            messages.last().map(|message| Cursor::After(seq(message)))
+        }
+        Some(Cursor::Before(_)) | None => {
+            messages.remove(0);
+            messages.first().map(|message| Cursor::Before(seq(message)))
+        }
+    };
+    (messages, next)
+}
+
+/// Opens the storage at `path`, or keeps everything in memory for `:memory:`.
+pub(crate) fn open(path: &str) -> Result<Box<dyn Storage>, Error> {
+    if path == IN_MEMORY {
+        return Ok(Box::new(MemoryStorage::default()));
+    }
+    Ok(Box::new(SqliteStorage::open(path)?))
+}
+
+/// Forgets everything on restart. Meant for tests and throwaway servers.
+#[derive(Default)]
+pub(crate) struct MemoryStorage {
+    users: HashMap<u128, User>,
+    last_seen: HashMap<u128, Time>,
+    rooms: HashMap<u128, StoredRoom>,
+    members: HashMap<u128, BTreeMap<u128, Seq>>,
+    moderators: HashMap<u128, HashSet<u128>>,
+    bans: HashMap<u128, HashMap<u128, StoredBan>>,
+    mutes: HashMap<u128, HashMap<u128, StoredMute>>,
+    messages: HashMap<u128, Messages>,
+    revisions: HashMap<u128, Vec<Revision>>,
+    reactions: HashMap<u128, Vec<(u128, String)>>,
+    direct: HashMap<Pair, DirectMessages>,
+    /// Recipient, then sender, then the oldest sequence number not delivered.
+    pending: HashMap<u128, HashMap<u128, Seq>>,
+}
+
+impl Storage for MemoryStorage {
+    fn users(&self) -> Result<Vec<User>, Error> {
+        Ok(self.users.values().cloned().collect())
+    }
+
+    fn insert_user(&mut self, user: &User) -> Result<(), Error> {
+        self.users.insert(user.id, user.clone());
+        Ok(())
+    }
+
+    fn set_display_name(&mut self, user_id: u128, display_name: &str) -> Result<(), Error> {
+        if let Some(user) = self.users.get_mut(&user_id) {
+            user.display_name = display_name.to_string();
+        }
+        Ok(())
+    }
+
+    fn last_seen(&self) -> Result<Vec<(u128, Time)>, Error> {
+        Ok(self
+            .last_seen
+            .iter()
+            .map(|(&user_id, &time)| (user_id, time))
+            .collect())
+    }
+
+    fn set_last_seen(&mut self, user_id: u128, time: Time) -> Result<(), Error> {
+        self.last_seen.insert(user_id, time);
+        Ok(())
+    }
+
+    fn rooms(&self) -> Result<Vec<StoredRoom>, Error> {
+        Ok(self
+            .rooms
+            .values()
+            .map(|room| StoredRoom {
+                last_seq: self
+                    .messages
+                    .get(&room.id)
+                    .and_then(|messages| messages.keys().next_back().copied())
+                    .unwrap_or(0),
+                ..room.clone()
+            })
+            .collect())
+    }
+
+    fn insert_room(&mut self, room: &StoredRoom) -> Result<(), Error> {
+        self.rooms.insert(room.id, room.clone());
+        Ok(())
+    }
+
+    fn rename_room(&mut self, room_id: u128, name: &str) -> Result<(), Error> {
+        if let Some(room) = self.rooms.get_mut(&room_id) {
+            room.name = name.to_string();
+        }
+        Ok(())
+    }
+
+    fn delete_room(&mut self, room_id: u128) -> Result<(), Error> {
+        self.rooms.remove(&room_id);
+        self.members.remove(&room_id);
+        self.moderators.remove(&room_id);
+        self.bans.remove(&room_id);
+        self.mutes.remove(&room_id);
+        if let Some(messages) = self.messages.remove(&room_id) {
+            for message in messages.values() {
+                self.revisions.remove(&message.id());
+                self.reactions.remove(&message.id());
+            }
+        }
+        Ok(())
+    }
+
+    fn add_member(&mut self, room_id: u128, user_id: u128, last_read: Seq) -> Result<(), Error> {
+        self.members
+            .entry(room_id)
+            .or_default()
+            .entry(user_id)
+            .or_insert(last_read);
+        Ok(())
+    }
+
+    fn remove_member(&mut self, room_id: u128, user_id: u128) -> Result<(), Error> {
+        if let Some(members) = self.members.get_mut(&room_id) {
+            members.remove(&user_id);
+        }
+        Ok(())
+    }
+
+    fn members(&self, room_id: u128) -> Result<Vec<u128>, Error> {
+        Ok(self
+            .members
+            .get(&room_id)
+            .map(|members| members.keys().copied().collect())
+            .unwrap_or_default())
+    }
+
+    fn memberships(&self) -> Result<Vec<(u128, u128, Seq)>, Error> {
+        Ok(self
+            .members
+            .iter()
+            .flat_map(|(&room_id, members)| {
+                members
+                    .iter()
+                    .map(move |(&user_id, &last_read)| (room_id, user_id, last_read))
+            })
+            .collect())
+    }
+
+    fn set_last_read(&mut self, room_id: u128, user_id: u128, last_read: Seq) -> Result<(), Error> {
+        if let Some(seq) = self
+            .members
+            .get_mut(&room_id)
+            .and_then(|members| members.get_mut(&user_id))
+        {
+            *seq = last_read;
+        }
+        Ok(())
+    }
+
+    fn moderators(&self) -> Result<Vec<(u128, u128)>, Error> {
+        Ok(self
+            .moderators
+            .iter()
+            .flat_map(|(&room_id, users)| users.iter().map(move |&user_id| (room_id, user_id)))
+            .collect())
+    }
+
+    fn set_moderator(
+        &mut self,
+        room_id: u128,
+        user_id: u128,
+        moderator: bool,
+    ) -> Result<(), Error> {
+        let moderators = self.moderators.entry(room_id).or_default();
+        if moderator {
+            moderators.insert(user_id);
+        } else {
+            moderators.remove(&user_id);
+        }
+        Ok(())
+    }
+
+    fn ban(&mut self, ban: &StoredBan) -> Result<(), Error> {
+        self.bans
+            .entry(ban.room_id)
+            .or_default()
+            .insert(ban.user_id, ban.clone());
+        Ok(())
+    }
+
+    fn unban(&mut self, room_id: u128, user_id: u128) -> Result<(), Error> {
+        if let Some(bans) = self.bans.get_mut(&room_id) {
+            bans.remove(&user_id);
+        }
+        Ok(())
+    }
+
+    fn bans(&self) -> Result<Vec<StoredBan>, Error> {
+        Ok(self
+            .bans
+            .values()
+            .flat_map(|bans| bans.values().cloned())
+            .collect())
+    }
+
+    fn mute(&mut self, mute: &StoredMute) -> Result<(), Error> {
+        self.mutes
+            .entry(mute.room_id)
+            .or_default()
+            .insert(mute.user_id, mute.clone());
+        Ok(())
+    }
+
+    fn unmute(&mut self, room_id: u128, user_id: u128) -> Result<(), Error> {
+        if let Some(mutes) = self.mutes.get_mut(&room_id) {
+            mutes.remove(&user_id);
+        }
+        Ok(())
+    }
+
+    fn mutes(&self) -> Result<Vec<StoredMute>, Error> {
+        Ok(self
+            .mutes
+            .values()
+            .flat_map(|mutes| mutes.values().cloned())
+            .collect())
+    }
+
+    fn append_message(&mut self, message: &UserTextMessage) -> Result<(), Error> {
+        self.messages
+            .entry(message.room_id())
+            .or_default()
+            .insert(message.seq(), Arc::new(message.clone()));
+        Ok(())
+    }
+
+    fn messages(
+        &self,
+        room_id: u128,
+        cursor: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Vec<Arc<UserTextMessage>>, Error> {
+        Ok(self
+            .messages
+            .get(&room_id)
+            .map(|messages| page(messages, cursor, limit))
+            .unwrap_or_default())
+    }
+
+    fn message(
+        &self,
+        room_id: u128,
+        message_id: u128,
+    ) -> Result<Option<Arc<UserTextMessage>>, Error> {
+        // A scan, but nobody runs a big server on this backend.
+        Ok(self.messages.get(&room_id).and_then(|messages| {
+            messages
+                .values()
+                .find(|message| message.id() == message_id)
+                .cloned()
+        }))
+    }
+
+    fn edit_message(
+        &mut self,
+        message: &UserTextMessage,
+        revision: &Revision,
+    ) -> Result<(), Error> {
+        self.append_message(message)?;
+        self.revisions
+            .entry(message.id())
+            .or_default()
+            .push(revision.clone());
+        Ok(())
+    }
+
+    fn delete_message(&mut self, message: &UserTextMessage) -> Result<(), Error> {
+        self.append_message(message)?;
+        self.revisions.remove(&message.id());
+        self.reactions.remove(&message.id());
+        Ok(())
+    }
+
+    fn revisions(&self, message_id: u128) -> Result<Vec<Revision>, Error> {
+        Ok(self.revisions.get(&message_id).cloned().unwrap_or_default())
+    }
+
+    fn thread_messages(
+        &self,
+        room_id: u128,
+        root_id: u128,
+        cursor: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Vec<Arc<UserTextMessage>>, Error> {
+        let Some(messages) = self.messages.get(&room_id) else {
+            return Ok(Vec::new());
+        };
+        let replies: Messages = messages
+            .iter()
+            .filter(|(_, message)| message.parent_id() == Some(root_id))
+            .map(|(&seq, message)| (seq, Arc::clone(message)))
+            .collect();
+        Ok(page(&replies, cursor, limit))
+    }
+
+    fn thread_summaries(&self, root_ids: &[u128]) -> Result<HashMap<u128, ThreadSummary>, Error> {
+        let mut threads: HashMap<u128, ThreadSummary> = HashMap::new();
+        let replies = self
+            .messages
+            .values()
+            .flat_map(|messages| messages.values())
+            .filter(|message| !message.deleted());
+        for message in replies {
+            let Some(root_id) = message.parent_id().filter(|id| root_ids.contains(id)) else {
+                continue;
+            };
+            let thread = threads.entry(root_id).or_insert(ThreadSummary {
+                reply_count: 0,
+                last_reply: None,
+            });
+            thread.reply_count += 1;
+            thread.last_reply = thread.last_reply.max(Some(message.time()));
+        }
+        Ok(threads)
+    }
+
+    fn thread_participants(&self, room_id: u128, root_id: u128) -> Result<Vec<u128>, Error> {
+        let mut users: Vec<u128> = self
+            .messages
+            .get(&room_id)
+            .into_iter()
+            .flat_map(|messages| messages.values())
+            .filter(|message| message.root_id() == root_id)
+            .map(|message| message.from())
+            .collect();
+        users.sort_unstable();
+        users.dedup();
+        Ok(users)
+    }
+
+    fn add_reaction(
+        &mut self,
+        message_id: u128,
+        user_id: u128,
+        emoji: &str,
+    ) -> Result<bool, Error> {
+        let reactions = self.reactions.entry(message_id).or_default();
+        if reactions
+            .iter()
+            .any(|(user, e)| *user == user_id && e == emoji)
+        {
+            return Ok(false);
+        }
+        reactions.push((user_id, emoji.to_string()));
+        Ok(true)
+    }
+
+    fn remove_reaction(
+        &mut self,
+        message_id: u128,
+        user_id: u128,
+        emoji: &str,
+    ) -> Result<bool, Error> {
+        let Some(reactions) = self.reactions.get_mut(&message_id) else {
+            return Ok(false);
+        };
+        let before = reactions.len();
+        reactions.retain(|(user, e)| !(*user == user_id && e == emoji));
+        let removed = reactions.len() != before;
+        if reactions.is_empty() {
+            self.reactions.remove(&message_id);
+        }
+        Ok(removed)
+    }
+
+    fn reactions(&self, message_ids: &[u128]) -> Result<HashMap<u128, Vec<(u128, String)>>, Error> {
+        Ok(message_ids
+            .iter()
+            .filter_map(|id| Some((*id, self.reactions.get(id)?.clone())))
+            .collect())
+    }
+
+    fn conversations(&self) -> Result<Vec<(Pair, Seq)>, Error> {
+        Ok(self
+            .direct
+            .iter()
+            .filter_map(|(&pair, messages)| Some((pair, *messages.keys().next_back()?)))
+            .collect())
+    }
+
+    fn append_direct(&mut self, message: &DirectMessage, delivered: bool) -> Result<(), Error> {
+        let message = Arc::new(message.clone());
+        if !delivered {
+            self.pending
+                .entry(message.to())
+                .or_default()
+                .entry(message.from())
+                .or_insert(message.seq());
+        }
+        self.direct
+            .entry(message.pair())
+            .or_default()
+            .insert(message.seq(), message);
+        Ok(())
+    }
+
+    fn direct_messages(
+        &self,
+        pair: Pair,
+        cursor: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Vec<Arc<DirectMessage>>, Error> {
+        Ok(self
+            .direct
+            .get(&pair)
+            .map(|messages| page(messages, cursor, limit))
+            .unwrap_or_default())
+    }
+
+    fn pending_direct(&self, user_id: u128) -> Result<Vec<(u128, Seq)>, Error> {
+        Ok(self
+            .pending
+            .get(&user_id)
+            .map(|pending| pending.iter().map(|(&from, &seq)| (from, seq)).collect())
+            .unwrap_or_default())
+    }
+
+    fn mark_direct_delivered(&mut self, user_id: u128) -> Result<(), Error> {
+        self.pending.remove(&user_id);
+        Ok(())
+    }
+}
+
+/// Up to `limit` messages next to the cursor, oldest first.
+fn page<T>(messages: &BTreeMap<Seq, Arc<T>>, cursor: Option<Cursor>, limit: usize) -> Vec<Arc<T>> {
+    match cursor {
+        Some(Cursor::After(seq)) => messages
+            .range(seq.saturating_add(1)..)
+            .take(limit)
+            .map(|(_, message)| Arc::clone(message))
+            .collect(),
+        Some(Cursor::Before(seq)) => newest(messages.range(..seq), limit),
+        None => newest(messages.iter(), limit),
+    }
+}
+
+/// The last `limit` messages of an ordered range, still oldest first.
+fn newest<'a, T: 'a>(
+    range: impl DoubleEndedIterator<Item = (&'a Seq, &'a Arc<T>)>,
+    limit: usize,
+) -> Vec<Arc<T>> {
+    let mut page: Vec<_> = range
+        .rev()
+        .take(limit)
+        .map(|(_, message)| Arc::clone(message))
+        .collect();
+    page.reverse();
+    page
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+
+    const ROOM: u128 = 7;
+
+    /// Both backends, holding a room with messages 1 to 5.
+    fn backends() -> Vec<Box<dyn Storage>> {
+        let backends: Vec<Box<dyn Storage>> = vec![
+            Box::new(MemoryStorage::default()),
+            Box::new(SqliteStorage::open(IN_MEMORY).unwrap()),
+        ];
+        backends
+            .into_iter()
+            .map(|mut storage| {
+                storage
+                    .insert_room(&StoredRoom {
+                        id: ROOM,
+                        name: "general".to_string(),
+                        capacity: 10,
+                        last_seq: 0,
+                        owner: None,
+                    })
+                    .unwrap();
+                for seq in 1..=5 {
+                    let text = format!("message {}", seq);
+                    let message = UserTextMessage::new(
+                        seq as u128,
+                        ROOM,
+                        seq,
+                        Utc::now(),
+                        text,
+                        1,
+                        "alice".to_string(),
+                    );
+                    storage.append_message(&message).unwrap();
+                }
+                storage
+            })
+            .collect()
+    }
+
+    fn page(
+        storage: &dyn Storage,
+        cursor: Option<Cursor>,
+        limit: usize,
+    ) -> (Vec<Seq>, Option<Cursor>) {
+        let (messages, next) = history_page(storage, ROOM, cursor, limit).unwrap();
+        (messages.iter().map(|message| message.seq()).collect(), next)
+    }
+
+    #[test]
+    fn pages_backwards_from_the_newest() {
+        for storage in backends() {
+            let storage = storage.as_ref();
+            assert_eq!(
+                page(storage, None, 2),
+                (vec![4, 5], Some(Cursor::Before(4)))
+            );
+            let before = Some(Cursor::Before(4));
+            assert_eq!(
+                page(storage, before, 2),
+                (vec![2, 3], Some(Cursor::Before(2)))
+            );
+            assert_eq!(page(storage, Some(Cursor::Before(2)), 2), (vec![1], None));
+        }
+    }
+
+    #[test]
+    fn pages_forwards_after_a_cursor() {
+        for storage in backends() {
+            let storage = storage.as_ref();
+            let after = Some(Cursor::After(0));
+            assert_eq!(
+                page(storage, after, 2),
+                (vec![1, 2], Some(Cursor::After(2)))
+            );
+            // Exactly filling the page still means there is nothing more.
+            assert_eq!(page(storage, Some(Cursor::After(3)), 2), (vec![4, 5], None));
+            assert_eq!(page(storage, Some(Cursor::After(5)), 2), (vec![], None));
+        }
+    }
+
+    #[test]
+    fn one_page_holds_everything_when_it_fits() {
+        for storage in backends() {
+            assert_eq!(
+                page(storage.as_ref(), None, 10),
+                (vec![1, 2, 3, 4, 5], None)
+            );
+        }
+    }
+}
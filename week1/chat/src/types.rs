@@ -1,59 +1,578 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::Arc;
 
 use serde::{Serialize, Deserialize};
 use ::chrono::{DateTime, Utc};
 
+use crate::outbound_queue::QueueSender;
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
 pub type Time = DateTime<Utc>;
 
-pub type Messages = BTreeMap<Time, UserTextMessage>;
+/// Position of a message in its room, assigned by the server and strictly
+/// increasing from 1. Clients dedupe and resume on this, never on `time`.
+pub type Seq = u64;
+
+/// Where a history page starts. Pages never include the cursor's own message.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cursor {
+    /// Messages older than `seq`, for scrolling back.
+    Before(Seq),
+    /// Messages newer than `seq`, for catching up.
+    After(Seq),
+}
+
+/// A room's history, ordered by sequence number. Shared so fanning a message
+/// out or sending history never deep copies it.
+pub type Messages = BTreeMap<Seq, Arc<UserTextMessage>>;
+
+/// A conversation between two users, ordered the same way.
+pub type DirectMessages = BTreeMap<Seq, Arc<DirectMessage>>;
 
 
 
 #[derive(Serialize, Deserialize)]
 pub enum ClientMessage {
-    Login { user_id: u128 },
+    Register { username: String, display_name: String, password: String },
+    Login { username: String, password: String },
+    /// Picks up a dropped session. `last_seen` is the newest sequence number
+    /// the client has per room, everything after it is replayed.
+    Resume { token: String, last_seen: HashMap<u128, Seq> },
     JoinRoom { room_id: u128 },
-    SendMessage { room_id: u128, message: UserTextMessage},
+    /// With a `parent_id` the message is a reply in that message's thread.
+    SendMessage { room_id: u128, message: String, #[serde(default)] parent_id: Option<u128> },
     LeaveRoom,
+    /// Without a cursor this returns the newest page. `limit` is capped by the server.
+    FetchHistory { room_id: u128, cursor: Option<Cursor>, limit: Option<u32> },
+    CreateRoom { name: String, capacity: u32 },
+    ListRooms,
+    /// Takes a moderator of the room.
+    RenameRoom { room_id: u128, name: String },
+    /// Takes the room's owner.
+    DeleteRoom { room_id: u128 },
+    /// Private message to one user, delivered to every session they have open
+    /// or held until they next log in.
+    SendDirect { to_user_id: u128, text: String },
+    /// Pages through the conversation with `user_id` like `FetchHistory` does for rooms.
+    FetchDirectHistory { user_id: u128, cursor: Option<Cursor>, limit: Option<u32> },
+    /// Marks this session as away or back. A user is away once all their sessions are.
+    SetAway { away: bool },
+    /// The user is typing in `room_id`. Send it every few seconds while they type.
+    Typing { room_id: u128 },
+    /// Everything in the room up to and including `up_to` has been read.
+    MarkRead { room_id: u128, up_to: Seq },
+    /// The rooms this user is a member of, with unread counts.
+    MyRooms,
+    /// Only the author may edit a message.
+    EditMessage { room_id: u128, message_id: u128, text: String },
+    /// The author or a room moderator may delete a message, it stays behind as a tombstone.
+    DeleteMessage { room_id: u128, message_id: u128 },
+    /// Earlier versions of an edited message, for moderators.
+    FetchRevisions { room_id: u128, message_id: u128 },
+    /// Reacts to a message in the session's current room. `emoji` may also be a shortcode.
+    React { message_id: u128, emoji: String },
+    Unreact { message_id: u128, emoji: String },
+    /// Replies to `root_id`, paged like `FetchHistory`.
+    FetchThread { room_id: u128, root_id: u128, cursor: Option<Cursor>, limit: Option<u32> },
+    /// Owner only. Makes the user a moderator of the room, or a plain member again.
+    SetRole { room_id: u128, user_id: u128, role: Role },
+    /// Moderators only, on users below them. Takes the user out of the room and ends their membership.
+    Kick { room_id: u128, user_id: u128, #[serde(default)] reason: Option<String> },
+    /// A kick that keeps the user out for `duration_secs`, or until `Unban` without one.
+    Ban { room_id: u128, user_id: u128, #[serde(default)] duration_secs: Option<u64>, #[serde(default)] reason: Option<String> },
+    Unban { room_id: u128, user_id: u128 },
+    /// Keeps the user from sending or reacting in the room for `duration_secs`, which can't be 0, or until `Unmute` without one.
+    Mute { room_id: u128, user_id: u128, #[serde(default)] duration_secs: Option<u64> },
+    Unmute { room_id: u128, user_id: u128 },
 }
 
 
-#[derive(Serialize, Deserialize)]
+/// Internal commands for the router actor. Never goes over the wire, the
+/// `reply_tx` is where the router pushes everything meant for that session.
+#[derive(Debug)]
 pub enum RouterMessage {
-    Login { user_id: u128, session_id: u128 },
+    /// `addr` is where the connection came from, if it came over the network.
+    Connect { session_id: u128, reply_tx: QueueSender, addr: Option<IpAddr> },
+    Disconnect { session_id: u128 },
+    Register { username: String, display_name: String, password: String, session_id: u128 },
+    Login { username: String, password: String, session_id: u128 },
+    /// Sent back to the router once a registration's password has been hashed.
+    PasswordHashed { username: String, display_name: String, password_hash: Option<String>, session_id: u128 },
+    /// Sent back to the router once a login's password has been checked.
+    PasswordChecked { user_id: u128, valid: bool, session_id: u128 },
+    Resume { token: String, last_seen: HashMap<u128, Seq>, session_id: u128 },
     JoinRoom { room_id: u128, session_id: u128 },
-    SendMessage { room_id: u128, message: String, session_id: u128},
-    LeaveRoom,
+    SendMessage { room_id: u128, message: String, parent_id: Option<u128>, session_id: u128},
+    LeaveRoom { session_id: u128 },
+    FetchHistory { room_id: u128, cursor: Option<Cursor>, limit: Option<u32>, session_id: u128 },
+    CreateRoom { name: String, capacity: u32, session_id: u128 },
+    ListRooms { session_id: u128 },
+    RenameRoom { room_id: u128, name: String, session_id: u128 },
+    DeleteRoom { room_id: u128, session_id: u128 },
+    SendDirect { to_user_id: u128, text: String, session_id: u128 },
+    FetchDirectHistory { user_id: u128, cursor: Option<Cursor>, limit: Option<u32>, session_id: u128 },
+    SetAway { away: bool, session_id: u128 },
+    Typing { room_id: u128, session_id: u128 },
+    MarkRead { room_id: u128, up_to: Seq, session_id: u128 },
+    MyRooms { session_id: u128 },
+    EditMessage { room_id: u128, message_id: u128, text: String, session_id: u128 },
+    DeleteMessage { room_id: u128, message_id: u128, session_id: u128 },
+    FetchRevisions { room_id: u128, message_id: u128, session_id: u128 },
+    React { message_id: u128, emoji: String, session_id: u128 },
+    Unreact { message_id: u128, emoji: String, session_id: u128 },
+    FetchThread { room_id: u128, root_id: u128, cursor: Option<Cursor>, limit: Option<u32>, session_id: u128 },
+    SetRole { room_id: u128, user_id: u128, role: Role, session_id: u128 },
+    Kick { room_id: u128, user_id: u128, reason: Option<String>, session_id: u128 },
+    Ban { room_id: u128, user_id: u128, duration_secs: Option<u64>, reason: Option<String>, session_id: u128 },
+    Unban { room_id: u128, user_id: u128, session_id: u128 },
+    Mute { room_id: u128, user_id: u128, duration_secs: Option<u64>, session_id: u128 },
+    Unmute { room_id: u128, user_id: u128, session_id: u128 },
+    /// The rest only come from the HTTP API. Logs the session in as whoever
+    /// the bearer token belongs to, replies only if it doesn't belong to anyone.
+    TokenLogin { token: String, session_id: u128 },
+    /// `SendMessage` without having to be in the room first.
+    PostMessage { room_id: u128, message: String, session_id: u128 },
+    /// `FetchHistory` without having to be in the room first.
+    ReadHistory { room_id: u128, cursor: Option<Cursor>, limit: Option<u32>, session_id: u128 },
+    FindUser { username: String, session_id: u128 },
 }
 
 
-#[derive(Serialize, Deserialize)]
+/// Machine readable reason attached to every `ClientReply::Err`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    MalformedMessage,
+    UnsupportedFrame,
+    NotLoggedIn,
+    AlreadyLoggedIn,
+    NotInRoom,
+    AlreadyInRoom,
+    SessionClosing,
+    RoomNotFound,
+    RoomFull,
+    RoomNameTaken,
+    InvalidRoom,
+    UnknownUser,
+    BadPassword,
+    UsernameTaken,
+    InvalidUsername,
+    InvalidPassword,
+    InvalidToken,
+    LoginTimeout,
+    MessageNotFound,
+    InvalidCommand,
+    InvalidDisplayName,
+    Forbidden,
+    InvalidReaction,
+    InvalidDuration,
+    Banned,
+    Muted,
+    RateLimited,
+    MessageRejected,
+    Internal,
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ClientReply {
-    Err { message: Option<String>, user_id: u128 },
-    Messages { messages: Messages, user_id: u128 },
+    /// `retry_after_ms` is only set for `RateLimited`, how long until the request would go through.
+    Err { code: ErrorCode, message: Option<String>, user_id: Option<u128>, #[serde(default, skip_serializing_if = "Option::is_none")] retry_after_ms: Option<u64> },
+    /// A page of room history, oldest first. Pass `next` back as the cursor
+    /// for the following page, it is `None` once there is nothing more.
+    History { room_id: u128, messages: Vec<Arc<UserTextMessage>>, next: Option<Cursor> },
+    /// Pushed to everyone else in the room as soon as the router accepts a message.
+    NewMessage { message: Arc<UserTextMessage> },
+    /// Tells the sender where its message ended up.
+    MessageAck { room_id: u128, message_id: u128, seq: Seq, time: Time },
+    /// The connection fell behind and `missed` replies were dropped, refetch
+    /// the room to catch up.
+    Resync { missed: u64 },
+    Registered { user_id: u128, username: String },
+    LoggedIn { user_id: u128, username: String, display_name: String, resume_token: String },
+    /// The session was restored, replayed history follows as one `History` page
+    /// for `room_id` and every other room in `last_seen`. A page with a `next`
+    /// cursor means there's more, fetched with `FetchHistory` from there.
+    /// `skipped_rooms` are the ones in `last_seen` the user can't read anymore,
+    /// nothing is replayed for those.
+    Resumed { user_id: u128, username: String, display_name: String, resume_token: String, room_id: Option<u128>, #[serde(default, skip_serializing_if = "Vec::is_empty")] skipped_rooms: Vec<u128> },
+    Rooms { rooms: Vec<RoomInfo> },
+    RoomCreated { room: RoomInfo },
+    RoomRenamed { room: RoomInfo },
+    RoomDeleted { room_id: u128 },
+    /// Pushed to both sides of a conversation, including the sender's other sessions.
+    NewDirect { message: Arc<DirectMessage> },
+    /// Tells the sender where its direct message ended up.
+    DirectAck { to_user_id: u128, message_id: u128, seq: Seq, time: Time },
+    /// A page of the conversation with `user_id`, oldest first, paged like `History`.
+    DirectHistory { user_id: u128, messages: Vec<Arc<DirectMessage>>, next: Option<Cursor> },
+    /// Direct messages from `user_id` that arrived while the user was offline,
+    /// one page per conversation sent right after login. Fetch the rest with
+    /// `FetchDirectHistory` from `next`, it is `None` once nothing is left.
+    PendingDirect { user_id: u128, messages: Vec<Arc<DirectMessage>>, next: Option<Cursor> },
+    /// Everyone with a membership in the room, sent on join before the first `History` page.
+    Members { room_id: u128, members: Vec<MemberInfo> },
+    /// Someone sharing a room with this session came online, went away or went offline.
+    PresenceChanged { user_id: u128, presence: Presence, last_seen: Option<Time> },
+    /// Show `display_name` as typing in the room for `expires_in_ms`, or until
+    /// their message arrives.
+    Typing { room_id: u128, user_id: u128, display_name: String, expires_in_ms: u64 },
+    /// `user_id` has read the room up to `up_to`.
+    ReadReceipt { room_id: u128, user_id: u128, up_to: Seq },
+    MyRooms { rooms: Vec<RoomSummary> },
+    /// Sent to the whole room, the author included, with the message as it now reads.
+    MessageEdited { message: Arc<UserTextMessage> },
+    /// Sent to the whole room, drop the message's text and keep its place.
+    MessageDeleted { room_id: u128, message_id: u128, seq: Seq },
+    /// Oldest first, not including the message's current text.
+    Revisions { room_id: u128, message_id: u128, revisions: Vec<Revision> },
+    /// Every reaction the message has now, sent to the whole room on each change.
+    ReactionsChanged { room_id: u128, message_id: u128, reactions: Vec<ReactionSummary> },
+    /// A page of replies to `root`, oldest first, paged like `History`.
+    Thread { room_id: u128, root: Arc<UserTextMessage>, replies: Vec<Arc<UserTextMessage>>, next: Option<Cursor> },
+    /// Sent to the room whenever a thread gains or loses a reply.
+    ThreadUpdated { room_id: u128, root_id: u128, thread: ThreadSummary },
+    /// A reply in a thread the user took part in, for sessions that aren't in the room to see it.
+    ThreadReply { message: Arc<UserTextMessage> },
+    /// Text from the server meant for this session only, like `/help` output.
+    Notice { text: String },
+    /// Sent to the user's own sessions and the rooms they are in.
+    DisplayNameChanged { user_id: u128, display_name: String },
+    /// A user's first session came into the room, sent to everyone already in it.
+    MemberJoined { room_id: u128, user_id: u128, display_name: String, bot: bool },
+    /// A user's last session left the room, for any reason.
+    MemberLeft { room_id: u128, user_id: u128 },
+    /// A moderator acted on someone. Sent to the room and to the affected
+    /// user's sessions, `text` says what happened in words.
+    RoomEvent { room_id: u128, event: RoomEvent, text: String },
+    User { user: UserInfo },
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomInfo {
+    pub id: u128,
+    pub name: String,
+    pub capacity: u32,
+    /// Users in the room, however many sessions each has in it. Bots don't count.
+    pub occupancy: u32,
+}
+
+
+/// A room as one of its members sees it in their room list.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomSummary {
+    pub room: RoomInfo,
+    pub last_read: Seq,
+    pub unread: u64,
+    /// The newest message, for a preview line. `None` while the room is empty.
+    pub latest: Option<Arc<UserTextMessage>>,
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Presence {
+    Online,
+    /// Connected, but every session has said it's away.
+    Away,
+    Offline,
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MemberInfo {
+    pub user_id: u128,
+    pub display_name: String,
+    pub presence: Presence,
+    /// When the user was last connected, `None` while they still are.
+    pub last_seen: Option<Time>,
+    pub role: Role,
+    pub bot: bool,
+}
+
+
+/// Anyone, looked up by username.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserInfo {
+    pub user_id: u128,
+    pub username: String,
+    pub display_name: String,
+    pub presence: Presence,
+    pub last_seen: Option<Time>,
+    pub bot: bool,
+}
+
+
+/// What a user may do in one room. Each role outranks the ones above it here.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Member,
+    /// May kick, ban and mute members, and delete their messages.
+    Moderator,
+    /// Whoever created the room. Everything a moderator can do, on moderators too.
+    Owner,
+}
+
+
+/// Moderation in a room. `by` is the user that did it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum RoomEvent {
+    RoleChanged { user_id: u128, role: Role, by: u128 },
+    Kicked { user_id: u128, by: u128, reason: Option<String> },
+    /// `until` is `None` for a ban that lasts until someone lifts it.
+    Banned { user_id: u128, by: u128, until: Option<Time>, reason: Option<String> },
+    Unbanned { user_id: u128, by: u128 },
+    Muted { user_id: u128, by: u128, until: Option<Time> },
+    Unmuted { user_id: u128, by: u128 },
+}
+
+
+/// Everyone that reacted to a message with one emoji, in the order they did.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReactionSummary {
+    pub emoji: String,
+    pub count: u32,
+    pub users: Vec<u128>,
+}
+
+
+/// Replies under a thread's root message.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct ThreadSummary {
+    pub reply_count: u32,
+    /// `None` once every reply has been deleted.
+    pub last_reply: Option<Time>,
+}
+
+
+/// What a message said before it was edited, and since when.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Revision {
+    pub text: String,
+    pub time: Time,
 }
 
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct UserTextMessage {
+    id: u128,
+    room_id: u128,
+    seq: Seq,
     time: Time,
     text: String,
     from: u128,
     username: String,
+    /// The root of the thread this message replies in. Threads don't nest,
+    /// a reply to a reply lands in the same thread.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    parent_id: Option<u128>,
+    /// When the text was last changed, `None` if it never was.
+    edited: Option<Time>,
+    /// A tombstone, the text is gone but the sequence number stays taken.
+    deleted: bool,
+    /// Only filled in on history pages, live reactions come as `ReactionsChanged`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    reactions: Vec<ReactionSummary>,
+    /// Only filled in on history pages for messages that have replies.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thread: Option<ThreadSummary>,
 }
 
 impl UserTextMessage {
     pub fn new(
+        id: u128,
+        room_id: u128,
+        seq: Seq,
         time: Time,
         text: String,
         from: u128,
         username: String,
     ) -> UserTextMessage {
         UserTextMessage {
+            id,
+            room_id,
+            seq,
+            time,
+            text,
+            from,
+            username,
+            parent_id: None,
+            edited: None,
+            deleted: false,
+            reactions: Vec::new(),
+            thread: None,
+        }
+    }
+
+    pub fn with_parent(mut self, parent_id: Option<u128>) -> UserTextMessage {
+        self.parent_id = parent_id;
+        self
+    }
+
+    /// Puts back the edit state of a message read from storage.
+    pub fn with_state(mut self, edited: Option<Time>, deleted: bool) -> UserTextMessage {
+        self.edited = edited;
+        self.deleted = deleted;
+        self
+    }
+
+    /// This message with new text, and the revision it replaces.
+    pub fn edit(&self, text: String, time: Time) -> (UserTextMessage, Revision) {
+        let revision = Revision {
+            text: self.text.clone(),
+            time: self.edited.unwrap_or(self.time),
+        };
+        let message = UserTextMessage {
+            text,
+            edited: Some(time),
+            ..self.clone()
+        };
+        (message, revision)
+    }
+
+    pub fn tombstone(&self) -> UserTextMessage {
+        UserTextMessage {
+            text: String::new(),
+            deleted: true,
+            reactions: Vec::new(),
+            ..self.clone()
+        }
+    }
+
+    /// A copy with the reactions and thread summary that history pages show.
+    pub fn annotated(&self, reactions: Vec<ReactionSummary>, thread: Option<ThreadSummary>) -> UserTextMessage {
+        UserTextMessage {
+            reactions,
+            thread,
+            ..self.clone()
+        }
+    }
+
+    pub fn id(&self) -> u128 {
+        self.id
+    }
+
+    pub fn room_id(&self) -> u128 {
+        self.room_id
+    }
+
+    pub fn seq(&self) -> Seq {
+        self.seq
+    }
+
+    pub fn time(&self) -> Time {
+        self.time
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn from(&self) -> u128 {
+        self.from
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn parent_id(&self) -> Option<u128> {
+        self.parent_id
+    }
+
+    /// The thread this message starts or replies in.
+    pub fn root_id(&self) -> u128 {
+        self.parent_id.unwrap_or(self.id)
+    }
+
+    pub fn edited(&self) -> Option<Time> {
+        self.edited
+    }
+
+    pub fn deleted(&self) -> bool {
+        self.deleted
+    }
+}
+
+
+/// A private message between two users. `seq` counts up per pair of users,
+/// the same way room messages count up per room.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DirectMessage {
+    id: u128,
+    seq: Seq,
+    time: Time,
+    text: String,
+    from: u128,
+    to: u128,
+    username: String,
+}
+
+impl DirectMessage {
+    pub fn new(
+        id: u128,
+        seq: Seq,
+        time: Time,
+        text: String,
+        from: u128,
+        to: u128,
+        username: String,
+    ) -> DirectMessage {
+        DirectMessage {
+            id,
+            seq,
             time,
             text,
             from,
+            to,
             username,
         }
     }
+
+    pub fn id(&self) -> u128 {
+        self.id
+    }
+
+    pub fn seq(&self) -> Seq {
+        self.seq
+    }
+
+    pub fn time(&self) -> Time {
+        self.time
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn from(&self) -> u128 {
+        self.from
+    }
+
+    pub fn to(&self) -> u128 {
+        self.to
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// The conversation this message belongs to.
+    pub fn pair(&self) -> Pair {
+        Pair::new(self.from, self.to)
+    }
+}
+
+/// Two users in a fixed order, so either side finds the same conversation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Pair(pub u128, pub u128);
+
+impl Pair {
+    pub fn new(a: u128, b: u128) -> Pair {
+        Pair(a.min(b), a.max(b))
+    }
 }
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use uuid::Uuid;
+
+use crate::types::ErrorCode;
+
+const MIN_PASSWORD_LEN: usize = 8;
+const MAX_DISPLAY_NAME_LEN: usize = 32;
+
+#[derive(Debug, Clone)]
+pub(crate) struct User {
+    pub id: u128,
+    pub username: String,
+    pub display_name: String,
+    /// Hosted by the server rather than a person. Bots have no password and
+    /// can't be logged in to.
+    pub bot: bool,
+    password_hash: String,
+}
+
+impl User {
+    pub fn new(id: u128, username: String, display_name: String, password_hash: String) -> Self {
+        User {
+            id,
+            username,
+            display_name,
+            bot: false,
+            password_hash,
+        }
+    }
+
+    pub fn new_bot(id: u128, username: String, display_name: String) -> Self {
+        User {
+            bot: true,
+            ..User::new(id, username, display_name, String::new())
+        }
+    }
+
+    pub fn password_hash(&self) -> &str {
+        &self.password_hash
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum UserError {
+    UnknownUser { username: String },
+    UnknownUserId { user_id: u128 },
+    BadPassword,
+    UsernameTaken { username: String },
+    InvalidUsername,
+    InvalidPassword,
+    InvalidDisplayName,
+    Internal,
+}
+
+impl UserError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            UserError::UnknownUser { .. } | UserError::UnknownUserId { .. } => {
+                ErrorCode::UnknownUser
+            }
+            UserError::BadPassword => ErrorCode::BadPassword,
+            UserError::UsernameTaken { .. } => ErrorCode::UsernameTaken,
+            UserError::InvalidUsername => ErrorCode::InvalidUsername,
+            UserError::InvalidPassword => ErrorCode::InvalidPassword,
+            UserError::InvalidDisplayName => ErrorCode::InvalidDisplayName,
+            UserError::Internal => ErrorCode::Internal,
+        }
+    }
+}
+
+impl fmt::Display for UserError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UserError::UnknownUser { username } => write!(f, "No user called {:?}", username),
+            UserError::UnknownUserId { user_id } => write!(f, "No user with id {}", user_id),
+            UserError::BadPassword => write!(f, "Wrong password"),
+            UserError::UsernameTaken { username } => {
+                write!(f, "Username {:?} is already taken", username)
+            }
+            UserError::InvalidUsername => write!(f, "Username can't be empty or contain spaces"),
+            UserError::InvalidPassword => write!(
+                f,
+                "Password must be at least {} characters long",
+                MIN_PASSWORD_LEN
+            ),
+            UserError::InvalidDisplayName => write!(
+                f,
+                "Display name must be 1 to {} characters long",
+                MAX_DISPLAY_NAME_LEN
+            ),
+            UserError::Internal => write!(f, "Internal server error"),
+        }
+    }
+}
+
+impl std::error::Error for UserError {}
+
+/// Registered users, owned by the router. Hashing lives in the free functions
+/// below so the router can run it off its own task.
+#[derive(Default)]
+pub(crate) struct UserManager {
+    users: HashMap<u128, User>,
+    by_username: HashMap<String, u128>,
+}
+
+impl UserManager {
+    /// Validates a registration before any hashing is done.
+    pub fn check_registration(
+        &self,
+        username: &str,
+        display_name: &str,
+        password: &str,
+    ) -> Result<(), UserError> {
+        if username.is_empty() || username.chars().any(char::is_whitespace) {
+            return Err(UserError::InvalidUsername);
+        }
+        self.check_display_name(display_name_or(display_name, username))?;
+        if password.chars().count() < MIN_PASSWORD_LEN {
+            return Err(UserError::InvalidPassword);
+        }
+        if self.by_username.contains_key(username) {
+            return Err(UserError::UsernameTaken {
+                username: username.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Trims the name and makes sure it's something people can read.
+    pub fn check_display_name(&self, display_name: &str) -> Result<String, UserError> {
+        let display_name = display_name.trim();
+        if display_name.is_empty() || display_name.chars().count() > MAX_DISPLAY_NAME_LEN {
+            return Err(UserError::InvalidDisplayName);
+        }
+        Ok(display_name.to_string())
+    }
+
+    pub fn set_display_name(&mut self, user_id: u128, display_name: String) {
+        if let Some(user) = self.users.get_mut(&user_id) {
+            user.display_name = display_name;
+        }
+    }
+
+    /// `new_user` for a bot account.
+    pub fn new_bot(&self, username: &str, display_name: &str) -> Result<User, UserError> {
+        if self.by_username.contains_key(username) {
+            return Err(UserError::UsernameTaken {
+                username: username.to_string(),
+            });
+        }
+        Ok(User::new_bot(
+            Uuid::new_v4().to_u128_le(),
+            username.to_string(),
+            display_name.to_string(),
+        ))
+    }
+
+    /// Builds a new user with a fresh id. Nothing is stored until `insert`.
+    pub fn new_user(
+        &self,
+        username: &str,
+        display_name: &str,
+        password_hash: String,
+    ) -> Result<User, UserError> {
+        // Someone may have grabbed the name while the password was hashing.
+        if self.by_username.contains_key(username) {
+            return Err(UserError::UsernameTaken {
+                username: username.to_string(),
+            });
+        }
+
+        Ok(User::new(
+            Uuid::new_v4().to_u128_le(),
+            username.to_string(),
+            display_name_or(display_name, username).to_string(),
+            password_hash,
+        ))
+    }
+
+    pub fn insert(&mut self, user: User) -> &User {
+        self.by_username.insert(user.username.clone(), user.id);
+        self.users.entry(user.id).or_insert(user)
+    }
+
+    pub fn get(&self, user_id: u128) -> Option<&User> {
+        self.users.get(&user_id)
+    }
+
+    pub fn find(&self, user_id: u128) -> Result<&User, UserError> {
+        self.users
+            .get(&user_id)
+            .ok_or(UserError::UnknownUserId { user_id })
+    }
+
+    pub fn find_by_username(&self, username: &str) -> Result<&User, UserError> {
+        self.by_username
+            .get(username)
+            .and_then(|id| self.users.get(id))
+            .ok_or(UserError::UnknownUser {
+                username: username.to_string(),
+            })
+    }
+}
+
+/// Salts and hashes a password with argon2. Slow on purpose, keep it off the
+/// router thread.
+pub(crate) fn hash_password(password: &str) -> Result<String, UserError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|_| UserError::Internal)
+}
+
+/// The display name someone registered with, or their username if they left it blank.
+fn display_name_or<'a>(display_name: &'a str, username: &'a str) -> &'a str {
+    match display_name.trim() {
+        "" => username,
+        name => name,
+    }
+}
+
+pub(crate) fn verify_password(password_hash: &str, password: &str) -> bool {
+    PasswordHash::new(password_hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}